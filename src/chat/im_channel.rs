@@ -1,9 +1,60 @@
-use crate::llm::local_llm::Token;
+use std::time::Duration;
+
+use super::settings::Settings;
+use crate::{
+    llm::local_llm::Token,
+    tool_env::{approval::Approval, ToolCall},
+};
 
 pub type Role = simple_llama::llm::Role;
 
 pub type Chunk = Token;
 
+/// Which conversation a message belongs to
+pub type ChatId = u32;
+
+#[derive(Clone, Debug)]
+pub enum Control {
+    /// drop the script engine state and start over
+    ResetScript,
+    /// the conversation was closed, forget its history and engine
+    DeleteChat,
+    /// forget the conversation from the user's `n`th message (0-based) on,
+    /// an edited version of it comes next
+    Rewind(usize),
+    /// the conversation's system prompt, in place of the preset ones
+    SystemPrompt(String),
+    /// the conversation was loaded from a file, its history is replaced
+    LoadHistory(Vec<(Role, String)>),
+    /// the conversation's scripts run in this engine from now on, from a fresh state.
+    /// The script executor sends it back once the engine is built.
+    SetEngine(String),
+    /// from the script executor: the engine `SetEngine` asked for could not be built
+    EngineError(String),
+    /// a script the user runs, the model doesn't see it or its result
+    RunScript(String),
+    ScriptResult {
+        code: String,
+        result: String,
+        duration: Duration,
+    },
+    /// snapshot of the variables living in the script engine
+    ScriptVariables(Vec<(String, String)>),
+    /// a script wants to call a tool with side effects
+    ConfirmCall {
+        code: String,
+        call: ToolCall,
+    },
+    ConfirmReply(Approval),
+    /// runtime options changed in the Settings tab
+    Settings(Settings),
+    /// from the script executor: the conversations that didn't pick an engine
+    /// with `/engine` run their scripts in this one now, from a fresh state
+    DefaultEngine(String),
+    /// from the LLM or the script thread: the settings could not be applied
+    SettingsError(String),
+}
+
 pub struct MessageConsumer {
     pub filter: fn(message: &Message) -> Option<Message>,
     pub tx: MessageTx,
@@ -11,6 +62,7 @@ pub struct MessageConsumer {
 
 #[derive(Clone, Debug)]
 pub struct Message {
+    pub chat_id: ChatId,
     pub role: Role,
     pub contont: Chunk,
 }
@@ -1 +1,2 @@
 pub mod im_channel;
+pub mod settings;
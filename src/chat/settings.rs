@@ -0,0 +1,38 @@
+//! Runtime options edited in the Settings tab.
+
+use std::path::Path;
+
+use toml_edit::{value, DocumentMut};
+
+use crate::tool_env::approval::ApprovalPolicy;
+
+/// Sent as [`super::im_channel::Control::Settings`]. The LLM thread picks up
+/// the temperature, and loads the model again for a new template or context size.
+/// The script executor picks up the approval policy and the engine, except from
+/// or to `none`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    // No top-p, top-k or repeat penalty: a simple_llama `ChatRequest` takes a single
+    // `SimpleOption`, and `SimpleOption::Temp` is the sampling option it is given.
+    // Another one would replace the temperature instead of adding to it.
+    pub temperature: f32,
+    pub template: String,
+    pub engine: String,
+    pub approval: ApprovalPolicy,
+    pub ctx_size: u32,
+}
+
+impl Settings {
+    /// Write the settings into the project file, keeping its comments and layout.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut doc: DocumentMut = std::fs::read_to_string(path)?.parse()?;
+        doc["template"] = value(self.template.as_str());
+        doc["engine"] = value(self.engine.as_str());
+        doc["approval"] = value(self.approval.as_str());
+        doc["run"]["ctx_size"] = value(self.ctx_size as i64);
+        // f32 -> f64 would write 0.699999988079071
+        doc["run"]["temperature"] = value(format!("{:.2}", self.temperature).parse::<f64>()?);
+        std::fs::write(path, doc.to_string())?;
+        Ok(())
+    }
+}
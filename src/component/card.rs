@@ -0,0 +1,109 @@
+//! One-line cards for the scripts the model runs and their results.
+
+use std::time::Duration;
+
+use ratatui::{
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+};
+
+use crate::tool_env::protocol::Action;
+
+const SUMMARY_CHARS: usize = 60;
+
+/// `s` cut to `max` chars, with an ellipsis when it was longer
+fn shorten(s: &str, max: usize) -> String {
+    match s.char_indices().nth(max) {
+        Some((i, _)) => format!("{}…", &s[..i]),
+        None => s.to_string(),
+    }
+}
+
+/// The call, or the first line of the script
+pub fn summary(action: &Action) -> String {
+    match action {
+        Action::Script(code) => {
+            let mut lines = code.lines().map(str::trim).filter(|line| !line.is_empty());
+            let first = shorten(lines.next().unwrap_or_default(), SUMMARY_CHARS);
+            match lines.count() {
+                0 => first,
+                n => format!("{first} (+{n} lines)"),
+            }
+        }
+        Action::Call { name, arguments } => {
+            let args: Vec<String> = match arguments {
+                serde_json::Value::Array(args) => args.iter().map(|arg| arg.to_string()).collect(),
+                serde_json::Value::Object(args) => args
+                    .iter()
+                    .map(|(name, arg)| format!("{name}: {arg}"))
+                    .collect(),
+                arg => vec![arg.to_string()],
+            };
+            let args = args.join(", ");
+            shorten(&format!("{name}({args})"), SUMMARY_CHARS)
+        }
+    }
+}
+
+/// `ok`, or `error` and its message, for a tool result
+fn status(result: &str) -> Span<'static> {
+    let value: serde_json::Value = match serde_json::from_str(result) {
+        Ok(value) => value,
+        // shortened for the model, it was not an error then
+        Err(_) => return Span::styled("ok", Style::new().fg(Color::Green)),
+    };
+    if value["status"] == "error" {
+        let message = value["error"].as_str().unwrap_or_default();
+        Span::styled(
+            format!("error: {}", shorten(message, SUMMARY_CHARS)),
+            Style::new().fg(Color::Red),
+        )
+    } else {
+        Span::styled("ok", Style::new().fg(Color::Green))
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{} ms", duration.as_millis())
+    } else {
+        format!("{:.1} s", duration.as_secs_f32())
+    }
+}
+
+pub struct Card<'a> {
+    /// the engine the script ran in
+    pub language: &'a str,
+    pub summary: String,
+    /// `None` until the result came back
+    pub result: Option<&'a str>,
+    pub duration: Option<Duration>,
+    pub expanded: bool,
+    pub selected: bool,
+}
+
+impl Card<'_> {
+    /// `▸ lua · send_msg(123, "hi") → ok · 12 ms`
+    pub fn header(&self) -> Line<'static> {
+        let dim = Style::new().fg(Color::DarkGray);
+        let mut line = Line::from(vec![
+            Span::raw(if self.expanded { "▾ " } else { "▸ " }),
+            Span::styled(self.language.to_string(), Style::new().fg(Color::Cyan)),
+            Span::styled(" · ", dim),
+            Span::raw(self.summary.clone()),
+            Span::styled(" → ", dim),
+            match self.result {
+                Some(result) => status(result),
+                None => Span::styled("…", Style::new().fg(Color::Yellow)),
+            },
+        ]);
+        if let Some(duration) = self.duration {
+            line.push_span(Span::styled(" · ", dim));
+            line.push_span(Span::styled(format_duration(duration), dim));
+        }
+        if self.selected {
+            line = line.reversed();
+        }
+        line
+    }
+}
@@ -1,78 +1,536 @@
-use std::collections::LinkedList;
+use std::{cell::RefCell, collections::LinkedList, rc::Rc, time::Duration};
 
-use crossterm::event::{Event, KeyCode, KeyModifiers, MouseEventKind};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEventKind};
 use ratatui::backend::Backend;
 use ratatui::style::{Color, Style, Stylize};
 use ratatui::Terminal;
 use ratatui::{
-    layout::{Constraint, Layout, Rect},
-    text::{Line, Text},
-    widgets::{Block, Paragraph},
+    layout::{Constraint, Layout, Margin, Rect},
+    text::Line,
+    widgets::{Block, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState, Wrap},
     Frame,
 };
 use simple_llama::llm::{Content, Role};
-use tui_textarea::TextArea;
+use tui_textarea::{CursorMove, TextArea};
 
-use crate::chat::im_channel::Message;
+use crate::chat::im_channel::{ChatId, Control, Message};
 use crate::llm::local_llm::Token;
+use crate::tool_env::protocol::Action;
+
+use super::card::{self, Card};
+use super::command::{self, Command, Completion};
+use super::confirm::ConfirmComponent;
+use super::editor;
+use super::history::InputHistory;
+use super::inspector::InspectorComponent;
+use super::keymap::{Action, Keymap};
+use super::markdown::{self, MessageFormat};
+
+/// A message laid out for a width, kept until the message or the width changes
+struct Rendered {
+    width: u16,
+    paragraph: Paragraph<'static>,
+    /// lines after wrapping
+    height: usize,
+}
+
+struct Entry {
+    content: Content,
+    /// the script and its result are shown, not only the card
+    expanded: bool,
+    /// how long the script ran, for tool results
+    duration: Option<Duration>,
+    /// the code of a result of `/run`
+    script: Option<String>,
+    /// the engine the message was written for, `/engine` may have changed it since
+    language: String,
+    rendered: Option<Rendered>,
+}
+
+impl Entry {
+    fn new(content: Content, language: &str) -> Self {
+        Entry {
+            content,
+            expanded: false,
+            duration: None,
+            script: None,
+            language: language.to_string(),
+            rendered: None,
+        }
+    }
+}
 
 pub struct MessagesComponent {
-    contents: LinkedList<Content>,
-    cursor: (u16, u16),
+    contents: Vec<Entry>,
+    format: MessageFormat,
+    /// the first visible line, counted after wrapping
+    scroll: usize,
+    /// lines of the view and of all the messages, as of the last render
+    height: usize,
+    total: usize,
+    /// the first line of each message, as of the last render
+    tops: Vec<usize>,
+    /// where the messages were drawn, to find what was clicked
+    inner: Rect,
     lock_on_bottom: bool,
+    /// something arrived while the view was not at the bottom
+    unseen: bool,
+    /// the card chosen with Tab
+    selected: Option<usize>,
+    /// keys scroll the messages instead of going to the input
+    pub(super) focused: bool,
     pub(super) wait_token: bool,
+    /// the script of the last reply runs, the model answers its result next
+    tool_running: bool,
 }
 
 impl MessagesComponent {
-    pub fn new(contents: LinkedList<Content>) -> Self {
+    pub fn new(contents: LinkedList<Content>, format: MessageFormat) -> Self {
         Self {
-            contents,
-            cursor: (0, 0),
+            contents: contents
+                .into_iter()
+                .map(|content| Entry::new(content, &format.language))
+                .collect(),
+            format,
+            scroll: 0,
+            height: 0,
+            total: 0,
+            tops: Vec::new(),
+            inner: Rect::default(),
             lock_on_bottom: true,
+            unseen: false,
+            selected: None,
+            focused: false,
             wait_token: false,
+            tool_running: false,
+        }
+    }
+
+    /// From the user's message until the model answered it, tool rounds included
+    pub(super) fn is_busy(&self) -> bool {
+        self.wait_token || self.tool_running
+    }
+
+    fn invalidate(&mut self, i: usize) {
+        if let Some(entry) = self.contents.get_mut(i) {
+            entry.rendered = None;
         }
     }
 
+    /// The card of a script shows its result, it changes with the message after it
+    fn invalidate_last(&mut self) {
+        let n = self.contents.len();
+        self.invalidate(n.wrapping_sub(1));
+        self.invalidate(n.wrapping_sub(2));
+    }
+
+    pub(super) fn push(&mut self, content: Content) {
+        self.contents
+            .push(Entry::new(content, &self.format.language));
+        self.invalidate_last();
+    }
+
+    /// The text of the last message, to change it
+    fn last_mut(&mut self) -> Option<&mut String> {
+        self.invalidate_last();
+        Some(&mut self.contents.last_mut()?.content.message)
+    }
+
+    /// How `entry` splits and is highlighted, in its own language
+    fn format_of(&self, entry: &Entry) -> MessageFormat {
+        MessageFormat {
+            protocol: self.format.protocol,
+            language: entry.language.clone(),
+        }
+    }
+
+    fn action(&self, i: usize) -> Option<Action> {
+        let entry = self.contents.get(i)?;
+        if entry.content.role != Role::Assistant {
+            return None;
+        }
+        self.format_of(entry).reply(&entry.content.message).action
+    }
+
+    /// The card message `i` is shown in: its own, or the one of the script it is the result of
+    fn card_of(&self, i: usize) -> Option<usize> {
+        let entry = self.contents.get(i)?;
+        match entry.content.role {
+            Role::Assistant => self.action(i).map(|_| i),
+            // a result of `/run` has a card of its own
+            Role::Tool if entry.script.is_none() && i > 0 && self.action(i - 1).is_some() => {
+                Some(i - 1)
+            }
+            Role::Tool => Some(i),
+            _ => None,
+        }
+    }
+
+    fn toggle(&mut self, card: usize) {
+        if let Some(entry) = self.contents.get_mut(card) {
+            entry.expanded = !entry.expanded;
+            self.invalidate(card);
+            self.invalidate(card + 1);
+        }
+    }
+
+    fn select(&mut self, selected: Option<usize>) {
+        if let Some(i) = self.selected {
+            self.invalidate(i);
+        }
+        self.selected = selected;
+        let Some(i) = selected else {
+            return;
+        };
+        self.invalidate(i);
+        // bring it into view
+        if let Some(&top) = self.tops.get(i) {
+            if top < self.scroll || top >= self.scroll + self.height {
+                self.scroll = top.min(self.max_scroll());
+                self.lock_on_bottom = self.scroll >= self.max_scroll();
+            }
+        }
+    }
+
+    /// Cards and the user's messages can be selected
+    fn selectable(&self, i: usize) -> bool {
+        self.contents[i].content.role == Role::User || self.card_of(i) == Some(i)
+    }
+
+    /// The next (`step` 1) or previous (`step` -1) selectable message from the selected one
+    fn select_next(&mut self, step: isize) {
+        let items: Vec<usize> = (0..self.contents.len())
+            .filter(|&i| self.selectable(i))
+            .collect();
+        if items.is_empty() {
+            return;
+        }
+        let next = match self
+            .selected
+            .and_then(|i| items.iter().position(|&item| item == i))
+        {
+            Some(n) => (n as isize + step).rem_euclid(items.len() as isize) as usize,
+            None if step > 0 => 0,
+            None => items.len() - 1,
+        };
+        self.select(Some(items[next]));
+    }
+
+    /// Drop message `i` and everything after it
+    pub(super) fn truncate(&mut self, i: usize) {
+        self.select(None);
+        self.contents.truncate(i);
+        self.invalidate_last();
+        self.lock_on_bottom = true;
+    }
+
+    /// The messages the model saw, for `/save`
+    pub(super) fn history(&self) -> Vec<(Role, String)> {
+        self.contents
+            .iter()
+            .filter(|entry| entry.script.is_none())
+            .filter_map(|entry| {
+                let role = match entry.content.role {
+                    Role::User => Role::User,
+                    Role::Assistant => Role::Assistant,
+                    Role::Tool => Role::Tool,
+                    _ => return None,
+                };
+                Some((role, entry.content.message.clone()))
+            })
+            .collect()
+    }
+
+    /// Replace the messages, after `/load`
+    pub(super) fn set_history(&mut self, history: Vec<(Role, String)>) {
+        self.truncate(0);
+        for (role, message) in history {
+            self.push(Content { role, message });
+        }
+    }
+
+    /// The scripts are in `language` from now on, after `/engine`.
+    /// The messages so far keep the language they were written for.
+    pub(super) fn set_language(&mut self, language: &str) {
+        self.format.language = language.to_string();
+    }
+
+    /// How many of the user's messages come before message `i`
+    pub(super) fn user_messages_before(&self, i: usize) -> usize {
+        self.contents[..i.min(self.contents.len())]
+            .iter()
+            .filter(|entry| entry.content.role == Role::User)
+            .count()
+    }
+
+    /// The text of message `i`, when it is the user's
+    pub(super) fn user_message(&self, i: usize) -> Option<&str> {
+        let entry = self.contents.get(i)?;
+        (entry.content.role == Role::User).then_some(entry.content.message.as_str())
+    }
+
+    fn max_scroll(&self) -> usize {
+        self.total.saturating_sub(self.height)
+    }
+
+    fn scroll_by(&mut self, delta: isize) {
+        self.scroll = self
+            .scroll
+            .saturating_add_signed(delta)
+            .min(self.max_scroll());
+        self.lock_on_bottom = self.scroll >= self.max_scroll();
+    }
+
+    fn scroll_to_bottom(&mut self) {
+        self.lock_on_bottom = true;
+    }
+
+    /// A message came in, flag it when the user is reading further up
+    fn arrived(&mut self) {
+        if !self.lock_on_bottom {
+            self.unseen = true;
+        }
+    }
+
+    /// The user's message to edit, when one was picked
+    pub fn handler_key(&mut self, input: KeyEvent) -> Option<usize> {
+        let ctrl = input.modifiers.contains(KeyModifiers::CONTROL);
+        let page = self.height.max(1) as isize;
+        match input.code {
+            KeyCode::Up | KeyCode::Char('k') => self.scroll_by(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.scroll_by(1),
+            KeyCode::Char('u') if ctrl => self.scroll_by(-page / 2),
+            KeyCode::Char('d') if ctrl => self.scroll_by(page / 2),
+            KeyCode::PageUp | KeyCode::Char('b') => self.scroll_by(-page),
+            KeyCode::PageDown | KeyCode::Char('f') | KeyCode::Char(' ') => self.scroll_by(page),
+            KeyCode::Home | KeyCode::Char('g') => self.scroll_by(-(self.scroll as isize)),
+            KeyCode::End | KeyCode::Char('G') => self.scroll_to_bottom(),
+            KeyCode::Tab => self.select_next(1),
+            KeyCode::BackTab => self.select_next(-1),
+            KeyCode::Enter | KeyCode::Char('e')
+                if self.selected.and_then(|i| self.user_message(i)).is_some() =>
+            {
+                return self.selected;
+            }
+            KeyCode::Enter => {
+                // without a selection, the latest card
+                let card = self.selected.or_else(|| {
+                    (0..self.contents.len())
+                        .rev()
+                        .find(|&i| self.card_of(i) == Some(i))
+                });
+                if let Some(card) = card {
+                    self.toggle(card);
+                }
+            }
+            KeyCode::Esc | KeyCode::Char('i') => {
+                self.focused = false;
+                self.select(None);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn lines(&self, i: usize) -> Vec<Line<'static>> {
+        let entry = &self.contents[i];
+        let content = &entry.content;
+        let style = match content.role {
+            Role::Assistant => Style::new().bg(Color::Cyan),
+            Role::User => Style::new().bg(Color::Yellow),
+            Role::Tool => Style::new().bg(Color::Gray),
+            _ => Style::new(),
+        };
+        let mut lines = vec![Line::styled(
+            format!("{}:", content.role.to_string().to_uppercase()),
+            style,
+        )];
+        let format = self.format_of(entry);
+        match content.role {
+            Role::Assistant => {
+                let reply = format.reply(&content.message);
+                lines.extend(markdown::markdown(&reply.text));
+                if let Some(action) = &reply.action {
+                    let result = self
+                        .contents
+                        .get(i + 1)
+                        .filter(|next| next.content.role == Role::Tool && next.script.is_none());
+                    let card = Card {
+                        language: &entry.language,
+                        summary: card::summary(action),
+                        result: result.map(|result| result.content.message.as_str()),
+                        duration: result.and_then(|result| result.duration),
+                        expanded: entry.expanded,
+                        selected: self.selected == Some(i),
+                    };
+                    lines.push(card.header());
+                    if entry.expanded {
+                        lines.extend(format.action(action));
+                    }
+                }
+            }
+            Role::Tool => match self.card_of(i) {
+                // the result of a script, shown when its card is expanded
+                Some(card) if card != i => {
+                    if !self.contents[card].expanded {
+                        return Vec::new();
+                    }
+                    lines.extend(markdown::tool(&content.message));
+                }
+                _ => {
+                    let script = entry.script.clone().map(Action::Script);
+                    let card = Card {
+                        language: &entry.language,
+                        summary: script
+                            .as_ref()
+                            .map_or_else(|| "result".to_string(), card::summary),
+                        result: Some(&content.message),
+                        duration: entry.duration,
+                        expanded: entry.expanded,
+                        selected: self.selected == Some(i),
+                    };
+                    lines.push(card.header());
+                    if entry.expanded {
+                        if let Some(script) = &script {
+                            lines.extend(format.action(script));
+                        }
+                        lines.extend(markdown::tool(&content.message));
+                    }
+                }
+            },
+            _ => {
+                if self.selected == Some(i) {
+                    lines[0] = Line::styled(
+                        format!(
+                            "{}: [Enter] edit and resend",
+                            content.role.to_string().to_uppercase()
+                        ),
+                        style.reversed(),
+                    );
+                }
+                lines.extend(
+                    content
+                        .message
+                        .lines()
+                        .map(|line| Line::styled(line.to_string(), style)),
+                )
+            }
+        }
+        lines.push(Line::default());
+        lines
+    }
+
+    fn layout(&mut self, width: u16) {
+        for i in 0..self.contents.len() {
+            let stale = self.contents[i]
+                .rendered
+                .as_ref()
+                .is_none_or(|rendered| rendered.width != width);
+            if stale {
+                let lines = self.lines(i);
+                // a result inside a collapsed card takes no room
+                let hidden = lines.is_empty();
+                let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false });
+                let height = if hidden {
+                    0
+                } else {
+                    paragraph.line_count(width)
+                };
+                self.contents[i].rendered = Some(Rendered {
+                    width,
+                    paragraph,
+                    height,
+                });
+            }
+        }
+        let mut top = 0;
+        self.tops = self
+            .contents
+            .iter()
+            .map(|entry| {
+                let start = top;
+                top += entry
+                    .rendered
+                    .as_ref()
+                    .map_or(0, |rendered| rendered.height);
+                start
+            })
+            .collect();
+        self.total = top;
+    }
+
+    /// The message at row `row` of the screen
+    fn message_at(&self, row: u16) -> Option<usize> {
+        if row < self.inner.y || row >= self.inner.y + self.inner.height {
+            return None;
+        }
+        let line = self.scroll + (row - self.inner.y) as usize;
+        if line >= self.total {
+            return None;
+        }
+        // the last message starting at or before the line
+        self.tops.iter().rposition(|&top| top <= line)
+    }
+
     pub fn render(&mut self, frame: &mut Frame, area: Rect)
     where
         Self: Sized,
     {
-        let mut text = Text::default();
-        for content in &self.contents {
-            let style = match content.role {
-                Role::Assistant => Style::new().bg(Color::Cyan),
-                Role::User => Style::new().bg(Color::Yellow),
-                Role::Tool => Style::new().bg(Color::Gray),
-                _ => Style::new(),
-            };
-            text.extend([Line::styled(
-                format!("{}:", content.role.to_string().to_uppercase()),
-                style,
-            )]);
-            text.extend(Text::raw(&content.message).style(style));
-            text.extend([Line::default().style(style)]);
+        self.height = area.height.saturating_sub(2) as usize;
+        self.layout(area.width.saturating_sub(2));
+        if self.lock_on_bottom || self.scroll >= self.max_scroll() {
+            self.lock_on_bottom = true;
+            self.unseen = false;
+            self.scroll = self.max_scroll();
         }
 
-        let line_n = text.lines.len();
+        let mut block = Block::bordered().title(if self.focused {
+            Line::raw("Messages [j/k PgUp/PgDn g/G, Tab select, Enter expand/edit, Esc back]")
+                .yellow()
+        } else {
+            Line::raw("Messages [F9 scroll]")
+        });
+        if self.unseen {
+            block =
+                block.title_bottom(Line::raw(" ↓ new messages below ").yellow().right_aligned());
+        }
+        let inner = block.inner(area);
+        self.inner = inner;
+        frame.render_widget(block, area);
 
-        let max_line = (area.height - 2 - 1) as usize;
-        if line_n > max_line {
-            let max_cursor = line_n - max_line;
-            if self.cursor.0 >= max_cursor as u16 {
-                self.lock_on_bottom = true;
+        // only the messages in view are drawn, the first one from where it is cut
+        let mut skip = self.scroll;
+        let mut y = inner.y;
+        for entry in &self.contents {
+            let Some(rendered) = &entry.rendered else {
+                continue;
+            };
+            if skip >= rendered.height {
+                skip -= rendered.height;
+                continue;
             }
-
-            if self.lock_on_bottom {
-                self.cursor.0 = max_cursor as u16;
+            let bottom = inner.y + inner.height;
+            if y >= bottom {
+                break;
             }
-        } else {
-            self.cursor.0 = 0;
+            let height = (rendered.height - skip).min((bottom - y) as usize) as u16;
+            let paragraph = rendered.paragraph.clone().scroll((skip as u16, 0));
+            frame.render_widget(paragraph, Rect::new(inner.x, y, inner.width, height));
+            y += height;
+            skip = 0;
         }
 
-        let paragraph = Paragraph::new(text)
-            .block(Block::bordered().title(format!("{:?}", self.cursor)))
-            .scroll(self.cursor);
-        frame.render_widget(paragraph, area);
+        if self.total > self.height {
+            let mut state = ScrollbarState::new(self.max_scroll()).position(self.scroll);
+            frame.render_stateful_widget(
+                Scrollbar::new(ScrollbarOrientation::VerticalRight),
+                area.inner(Margin {
+                    vertical: 1,
+                    horizontal: 0,
+                }),
+                &mut state,
+            );
+        }
     }
 
     pub fn handler_input(&mut self, input: Input) {
@@ -80,9 +538,11 @@ impl MessagesComponent {
             Input::Message(Message {
                 role: Role::Assistant,
                 contont: Token::Start,
+                ..
             }) => {
                 self.wait_token = true;
-                self.contents.push_back(Content {
+                self.arrived();
+                self.push(Content {
                     role: Role::Assistant,
                     message: String::with_capacity(64),
                 })
@@ -90,45 +550,81 @@ impl MessagesComponent {
             Input::Message(Message {
                 role: Role::Assistant,
                 contont: Token::Chunk(chunk),
+                ..
             }) => {
-                if let Some(content) = self.contents.back_mut() {
-                    content.message.push_str(&chunk);
+                if let Some(message) = self.last_mut() {
+                    message.push_str(&chunk);
                 }
             }
             Input::Message(Message {
                 role: Role::Assistant,
                 contont: Token::End(chunk),
+                ..
             }) => {
                 self.wait_token = false;
-                if let Some(content) = self.contents.back_mut() {
-                    content.message = chunk;
+                if let Some(message) = self.last_mut() {
+                    *message = chunk;
+                }
+                // the executor splits the reply with the engine it has now
+                let language = self.format.language.clone();
+                if let Some(entry) = self.contents.last_mut() {
+                    entry.language = language;
                 }
+                // the executor runs the script the reply has, if there is an engine
+                let last = self.contents.len().wrapping_sub(1);
+                self.tool_running = self.format.language != "none" && self.action(last).is_some();
             }
 
             Input::Message(Message {
                 role: Role::Tool,
-                contont: Token::End(chunk),
+                contont:
+                    Token::ToolEnd {
+                        result,
+                        full,
+                        duration,
+                    },
+                ..
             }) => {
-                self.contents.push_back(Content {
+                self.tool_running = false;
+                self.wait_token = true;
+                self.arrived();
+                // the model got less, show the user all of it
+                self.push(Content {
                     role: Role::Tool,
-                    message: chunk,
+                    message: full.unwrap_or(result),
                 });
+                if let Some(entry) = self.contents.last_mut() {
+                    entry.duration = Some(duration);
+                }
             }
 
-            Input::Event(Event::Mouse(event)) => match event.kind {
-                MouseEventKind::ScrollDown => {
-                    if event.modifiers.contains(KeyModifiers::CONTROL) {
-                        self.cursor.1 += 1;
-                    } else {
-                        self.cursor.0 += 1;
-                    }
+            Input::Message(Message {
+                role: Role::Tool,
+                contont:
+                    Token::Control(Control::ScriptResult {
+                        code,
+                        result,
+                        duration,
+                    }),
+                ..
+            }) => {
+                self.arrived();
+                self.push(Content {
+                    role: Role::Tool,
+                    message: result,
+                });
+                if let Some(entry) = self.contents.last_mut() {
+                    entry.script = Some(code);
+                    entry.duration = Some(duration);
                 }
-                MouseEventKind::ScrollUp => {
-                    if event.modifiers.contains(KeyModifiers::CONTROL) {
-                        self.cursor.1 = self.cursor.1.max(1) - 1;
-                    } else {
-                        self.cursor.0 = self.cursor.0.max(1) - 1;
-                        self.lock_on_bottom = false;
+            }
+
+            Input::Event(Event::Mouse(event)) => match event.kind {
+                MouseEventKind::ScrollDown => self.scroll_by(1),
+                MouseEventKind::ScrollUp => self.scroll_by(-1),
+                MouseEventKind::Down(MouseButton::Left) => {
+                    if let Some(card) = self.message_at(event.row).and_then(|i| self.card_of(i)) {
+                        self.toggle(card);
                     }
                 }
                 _ => {}
@@ -139,11 +635,26 @@ impl MessagesComponent {
 }
 
 pub struct ChatComponent {
+    chat_id: ChatId,
     user_tx: crossbeam::channel::Sender<Message>,
     messages: MessagesComponent,
+    inspector: InspectorComponent,
+    confirm: ConfirmComponent,
     input: TextArea<'static>,
-    exit_n: u8,
-    pub event: String,
+    /// the earlier message of the user being edited in the input
+    editing: Option<usize>,
+    /// what the last command did, or why it failed
+    status: Option<Result<String, String>>,
+    /// set by `/temp`, applied by the app to every conversation
+    temperature: Option<f32>,
+    /// `/engine` picked the engine, the one in the settings doesn't apply
+    own_engine: bool,
+    history: Rc<RefCell<InputHistory>>,
+    /// the entry of the history in the input, while going through it
+    recalled: Option<usize>,
+    /// the input before going through the history
+    draft: Vec<String>,
+    keymap: Rc<Keymap>,
 }
 
 #[derive(Debug)]
@@ -154,14 +665,27 @@ pub enum Input {
 
 impl ChatComponent {
     pub fn new(
+        chat_id: ChatId,
         contents: LinkedList<Content>,
+        format: MessageFormat,
+        history: Rc<RefCell<InputHistory>>,
+        keymap: Rc<Keymap>,
         user_tx: crossbeam::channel::Sender<Message>,
     ) -> Self {
         Self {
-            messages: MessagesComponent::new(contents),
+            chat_id,
+            messages: MessagesComponent::new(contents, format),
+            inspector: InspectorComponent::new(),
+            confirm: ConfirmComponent::new(),
             input: Self::new_textarea(),
-            exit_n: 0,
-            event: String::new(),
+            editing: None,
+            status: None,
+            temperature: None,
+            own_engine: false,
+            history,
+            recalled: None,
+            draft: Vec::new(),
+            keymap,
             user_tx,
         }
     }
@@ -173,59 +697,372 @@ impl ChatComponent {
         let vertical = Layout::vertical([Constraint::Min(5), Constraint::Max(10)]);
         let [messages_area, input_area] = vertical.areas(area);
 
-        self.messages.render(frame, messages_area);
-        if self.messages.wait_token {
-            self.input
-                .set_block(Block::bordered().title("Input").yellow())
+        if self.inspector.visible {
+            let horizontal =
+                Layout::horizontal([Constraint::Percentage(70), Constraint::Percentage(30)]);
+            let [messages_area, inspector_area] = horizontal.areas(messages_area);
+            self.messages.render(frame, messages_area);
+            self.inspector.render(frame, inspector_area);
         } else {
-            self.input
-                .set_block(Block::bordered().title("Input").gray())
+            self.messages.render(frame, messages_area);
+        }
+        let send = self.keymap.keys(Action::Send);
+        let title = if self.editing.is_some() {
+            format!("Input (editing an earlier message, [{send}] resend, [Esc] cancel)")
+        } else {
+            let editor = self.keymap.keys(Action::Editor);
+            format!("Input ([{send}] send, [Up/Down] history, [{editor}] $EDITOR)")
+        };
+        let mut block = Block::bordered().title(title);
+        match &self.status {
+            Some(Ok(status)) => block = block.title_bottom(Line::raw(status.clone()).green()),
+            Some(Err(err)) => block = block.title_bottom(Line::raw(err.clone()).red()),
+            None => {}
+        }
+        if self.messages.is_busy() {
+            self.input.set_block(block.yellow())
+        } else {
+            self.input.set_block(block.gray())
         }
         frame.render_widget(self.input.widget(), input_area);
+
+        if self.confirm.is_active() {
+            self.confirm.render(frame, area);
+        }
+    }
+
+    /// Keys are text for the input box, not actions, unless they are shortcuts
+    pub fn is_typing(&self) -> bool {
+        !self.messages.focused && self.input.lines().iter().any(|line| !line.is_empty())
+    }
+
+    /// The model is answering this conversation
+    pub fn is_waiting(&self) -> bool {
+        self.messages.is_busy()
     }
 
     fn new_textarea() -> TextArea<'static> {
         TextArea::default()
     }
 
+    fn edit_message(&mut self, i: usize) {
+        let Some(message) = self.messages.user_message(i) else {
+            return;
+        };
+        let lines = message.lines().map(str::to_string).collect();
+        self.input = TextArea::new(lines);
+        self.input.move_cursor(CursorMove::Bottom);
+        self.input.move_cursor(CursorMove::End);
+        self.editing = Some(i);
+        self.recalled = None;
+        self.messages.focused = false;
+    }
+
+    fn set_input(&mut self, text: &str) {
+        self.input = TextArea::new(text.lines().map(str::to_string).collect());
+        self.input.move_cursor(CursorMove::Bottom);
+        self.input.move_cursor(CursorMove::End);
+    }
+
+    /// Up on the first line of the input brings back what was sent before it
+    fn recall_older(&mut self) -> bool {
+        let i = match self.recalled {
+            Some(i) => i.saturating_sub(1),
+            None => match self.history.borrow().len().checked_sub(1) {
+                Some(i) => i,
+                None => return false,
+            },
+        };
+        if self.recalled.is_none() {
+            self.draft = self.input.lines().to_vec();
+        }
+        let entry = self.history.borrow().get(i).unwrap_or_default().to_string();
+        self.set_input(&entry);
+        self.recalled = Some(i);
+        true
+    }
+
+    /// Down on the last line of the input goes back towards the draft
+    fn recall_newer(&mut self) -> bool {
+        let Some(i) = self.recalled else {
+            return false;
+        };
+        let entry = self.history.borrow().get(i + 1).map(str::to_string);
+        match entry {
+            Some(entry) => {
+                self.set_input(&entry);
+                self.recalled = Some(i + 1);
+            }
+            None => {
+                let draft = std::mem::take(&mut self.draft).join("\n");
+                self.set_input(&draft);
+                self.recalled = None;
+            }
+        }
+        true
+    }
+
+    fn open_editor(&mut self) {
+        match editor::edit(&self.input.lines().join("\n")) {
+            Ok(text) => {
+                self.set_input(&text);
+                self.status = None;
+            }
+            Err(err) => self.status = Some(Err(format!("editor: {err:#}"))),
+        }
+    }
+
+    /// The temperature `/temp` set, once
+    pub fn take_temperature(&mut self) -> Option<f32> {
+        self.temperature.take()
+    }
+
+    /// The engine in the settings changed, the executor started this conversation's over
+    pub fn set_default_language(&mut self, language: &str) {
+        if !self.own_engine {
+            self.messages.set_language(language);
+        }
+    }
+
+    fn send_control(&self, control: Control) {
+        let _ = self.user_tx.send(Message {
+            chat_id: self.chat_id,
+            role: Role::User,
+            contont: Token::Control(control),
+        });
+    }
+
+    /// What the command did, for the status line
+    fn run_command(&mut self, command: Command) -> Result<String, String> {
+        let busy = || "wait until the model has answered".to_string();
+        let no_engine = || "no script engine is running, set `engine` in the project".to_string();
+        match command {
+            Command::Clear => {
+                if self.messages.is_busy() {
+                    return Err(busy());
+                }
+                self.send_control(Control::Rewind(0));
+                self.send_control(Control::ResetScript);
+                self.messages.truncate(0);
+                Ok("cleared, the script variables too".to_string())
+            }
+            Command::Save(path) => {
+                let history = self.messages.history();
+                command::save(&path, &history).map_err(|err| format!("{err:#}"))?;
+                Ok(format!(
+                    "saved {} messages to {}",
+                    history.len(),
+                    path.display()
+                ))
+            }
+            Command::Load(path) => {
+                if self.messages.is_busy() {
+                    return Err(busy());
+                }
+                let history =
+                    command::load(&path).map_err(|err| format!("{}: {err:#}", path.display()))?;
+                let n = history.len();
+                self.send_control(Control::LoadHistory(history.clone()));
+                self.messages.set_history(history);
+                Ok(format!("loaded {n} messages from {}", path.display()))
+            }
+            Command::Engine(language) => {
+                if self.messages.format.language == "none" {
+                    return Err(no_engine());
+                }
+                // switched when the executor has built the engine
+                self.send_control(Control::SetEngine(language.clone()));
+                Ok(format!("starting {language}"))
+            }
+            Command::Temp(temperature) => {
+                self.temperature = Some(temperature);
+                Ok(format!("temperature {temperature:.2}"))
+            }
+            Command::System(prompt) => {
+                self.send_control(Control::SystemPrompt(prompt));
+                Ok("system prompt set".to_string())
+            }
+            Command::Run(code) => {
+                if self.messages.format.language == "none" {
+                    return Err(no_engine());
+                }
+                if self.messages.is_busy() {
+                    return Err(busy());
+                }
+                self.send_control(Control::RunScript(code));
+                Ok("running".to_string())
+            }
+            Command::Help => {
+                self.messages.push(Content {
+                    role: Role::System,
+                    message: command::help(),
+                });
+                self.messages.lock_on_bottom = true;
+                Ok(String::new())
+            }
+        }
+    }
+
+    /// Tab on a command completes it
+    fn complete_command(&mut self) -> bool {
+        if self.input.lines().len() != 1 {
+            return false;
+        }
+        match command::complete(&self.input.lines()[0]) {
+            Some(Completion::Done(line)) => {
+                self.input = TextArea::new(vec![line]);
+                self.input.move_cursor(CursorMove::End);
+                self.status = None;
+                true
+            }
+            Some(Completion::Candidates(candidates)) => {
+                self.status = Some(Ok(candidates.join("  ")));
+                true
+            }
+            None => false,
+        }
+    }
+
     fn submit_message(&mut self) {
-        let mut new_textarea = Self::new_textarea();
-        std::mem::swap(&mut self.input, &mut new_textarea);
-        let lines = new_textarea.into_lines();
-        let message = lines.join("\n");
+        let message = self.input.lines().join("\n");
+        if let Some(command) = command::parse(&message) {
+            // a failed command stays in the input to be fixed
+            self.status = Some(command.and_then(|command| self.run_command(command)));
+            if let Some(Ok(_)) = self.status {
+                self.history.borrow_mut().push(&message);
+                self.input = Self::new_textarea();
+                self.recalled = None;
+                self.editing = None;
+            }
+            return;
+        }
+        self.status = None;
+        if self.messages.is_busy() {
+            return;
+        }
+        self.history.borrow_mut().push(&message);
+        self.input = Self::new_textarea();
+        self.recalled = None;
+
+        // the model forgets the edited message and what came after it
+        if let Some(i) = self.editing.take() {
+            self.send_control(Control::Rewind(self.messages.user_messages_before(i)));
+            self.messages.truncate(i);
+            // the scripts that ran after it may have set variables the new answer relies on
+            self.status = Some(Ok(format!(
+                "resent, the script variables are kept, [{}] resets them",
+                self.keymap.keys(Action::ResetScript)
+            )));
+        }
 
         self.user_tx
             .send(Message {
+                chat_id: self.chat_id,
                 role: Role::User,
                 contont: Token::End(message.clone()),
             })
             .unwrap();
 
-        self.messages.contents.push_back(Content {
+        self.messages.push(Content {
             role: Role::User,
             message,
         });
         self.messages.lock_on_bottom = true;
     }
 
-    pub fn handler_input<B: Backend>(&mut self, terminal: &mut Terminal<B>, input: Input) -> bool {
-        self.event = format!("{:?}", input);
+    /// The action left for the app, [`Action::Help`] or [`Action::Quit`]
+    pub fn handler_input<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        input: Input,
+    ) -> Option<Action> {
+        let action = match &input {
+            Input::Event(Event::Key(key)) => self.keymap.action(key, self.is_typing()),
+            _ => None,
+        };
         match input {
-            Input::Event(Event::Key(input)) if input.code == KeyCode::F(5) => {
+            Input::Message(Message {
+                contont: Token::Control(Control::ConfirmCall { code, call }),
+                ..
+            }) => {
+                self.confirm.open(code, call);
+            }
+            Input::Event(Event::Key(input)) if self.confirm.is_active() => {
+                if let Some(approval) = self.confirm.handler_key(input) {
+                    self.send_control(Control::ConfirmReply(approval));
+                }
+            }
+            Input::Event(Event::Key(_)) if action == Some(Action::Help) => return action,
+            Input::Event(Event::Key(_)) if action == Some(Action::ClearScreen) => {
+                let _ = terminal.clear();
+            }
+            Input::Event(Event::Key(_)) if action == Some(Action::Send) => {
+                self.submit_message();
+            }
+            Input::Event(Event::Key(_)) if action == Some(Action::Editor) => {
+                self.open_editor();
                 let _ = terminal.clear();
             }
+            Input::Event(Event::Key(_)) if action == Some(Action::ResetScript) => {
+                self.send_control(Control::ResetScript);
+            }
+            Input::Event(Event::Key(_)) if action == Some(Action::Inspector) => {
+                self.inspector.visible = !self.inspector.visible;
+            }
+            Input::Event(Event::Key(_)) if action == Some(Action::FocusMessages) => {
+                self.messages.focused = !self.messages.focused;
+            }
             Input::Event(Event::Key(input))
-                if (input.code == KeyCode::Char('s')
-                    && input.modifiers.contains(KeyModifiers::CONTROL)) =>
+                if self.messages.focused
+                    || matches!(input.code, KeyCode::PageUp | KeyCode::PageDown) =>
             {
-                if !self.messages.wait_token {
-                    self.submit_message();
+                if let Some(i) = self.messages.handler_key(input) {
+                    if self.messages.is_busy() {
+                        self.status = Some(Err("wait until the model has answered".to_string()));
+                    } else {
+                        self.edit_message(i);
+                    }
                 }
             }
-            Input::Event(Event::Key(input)) if input.code == KeyCode::Esc => {
-                self.exit_n += 50;
-                return self.exit_n < 100;
+            Input::Message(Message {
+                contont: Token::Control(Control::ScriptVariables(variables)),
+                ..
+            }) => {
+                self.inspector.set_variables(variables);
             }
+            Input::Message(Message {
+                contont: Token::Control(Control::SetEngine(language)),
+                ..
+            }) => {
+                self.messages.set_language(&language);
+                self.own_engine = true;
+                self.status = Some(Ok(format!("the scripts run in {language} now")));
+            }
+            Input::Message(Message {
+                contont: Token::Control(Control::EngineError(err)),
+                ..
+            }) => {
+                self.status = Some(Err(err));
+            }
+            Input::Event(Event::Key(input))
+                if input.code == KeyCode::Esc && self.editing.is_some() =>
+            {
+                self.editing = None;
+                self.input = Self::new_textarea();
+            }
+            Input::Event(Event::Key(_)) if action == Some(Action::Quit) => return action,
+            Input::Event(Event::Key(input))
+                if input.code == KeyCode::Tab && self.complete_command() => {}
+            Input::Event(Event::Key(input))
+                if input.code == KeyCode::Up
+                    && input.modifiers.is_empty()
+                    && self.input.cursor().0 == 0
+                    && self.recall_older() => {}
+            Input::Event(Event::Key(input))
+                if input.code == KeyCode::Down
+                    && input.modifiers.is_empty()
+                    && self.input.cursor().0 + 1 == self.input.lines().len()
+                    && self.recall_newer() => {}
             Input::Event(Event::Key(input)) => {
                 self.input.input(input);
             }
@@ -233,7 +1070,6 @@ impl ChatComponent {
                 self.messages.handler_input(input);
             }
         }
-        self.exit_n = self.exit_n.max(1) - 1;
-        true
+        None
     }
 }
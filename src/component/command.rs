@@ -0,0 +1,264 @@
+//! Slash commands typed in the input box, handled without the model.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use simple_llama::llm::Role;
+
+/// The script engines `/engine` switches between
+pub const ENGINES: [&str; 3] = ["lua", "rhai", "js"];
+
+/// `(usage, what it does)`, for `/help` and the completion
+pub const COMMANDS: [(&str, &str); 8] = [
+    ("/clear", "forget this conversation"),
+    ("/save <file>", "write this conversation to a new JSON file"),
+    (
+        "/load <file>",
+        "replace this conversation with one written by /save",
+    ),
+    (
+        "/engine lua|rhai|js",
+        "run the scripts of this conversation in another engine, from a fresh state",
+    ),
+    ("/temp <0-2>", "set the temperature of the model"),
+    (
+        "/system <text>",
+        "set the system prompt of this conversation",
+    ),
+    (
+        "/run <code>",
+        "run code in the script engine, the model doesn't see it",
+    ),
+    ("/help", "list the commands"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Clear,
+    Save(PathBuf),
+    Load(PathBuf),
+    Engine(String),
+    Temp(f32),
+    System(String),
+    Run(String),
+    Help,
+}
+
+fn name(usage: &str) -> &str {
+    usage.split_whitespace().next().unwrap_or(usage)
+}
+
+fn usage(command: &str) -> String {
+    let usage = COMMANDS
+        .iter()
+        .find(|(usage, _)| name(usage) == command)
+        .map_or(command, |(usage, _)| usage);
+    format!("usage: {usage}")
+}
+
+/// The command in `input`, `None` when it is a message for the model
+pub fn parse(input: &str) -> Option<Result<Command, String>> {
+    let input = input.trim();
+    // `/` alone or `//...` is still a message
+    if !input.starts_with('/') || !input[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let (command, arg) = match input.split_once(char::is_whitespace) {
+        Some((command, arg)) => (command, arg.trim()),
+        None => (input, ""),
+    };
+    let required = |arg: &str| {
+        if arg.is_empty() {
+            Err(usage(command))
+        } else {
+            Ok(arg.to_string())
+        }
+    };
+    Some(match command {
+        "/clear" => Ok(Command::Clear),
+        "/save" => required(arg).map(|path| Command::Save(path.into())),
+        "/load" => required(arg).map(|path| Command::Load(path.into())),
+        "/engine" if ENGINES.contains(&arg) => Ok(Command::Engine(arg.to_string())),
+        "/engine" => Err(format!(
+            "unknown engine `{arg}`, one of {}",
+            ENGINES.join(", ")
+        )),
+        "/temp" => match arg.parse::<f32>() {
+            Ok(temp) if (0.0..=2.0).contains(&temp) => Ok(Command::Temp(temp)),
+            _ => Err(format!("{}, a number from 0 to 2", usage(command))),
+        },
+        "/system" => required(arg).map(Command::System),
+        "/run" => required(arg).map(Command::Run),
+        "/help" => Ok(Command::Help),
+        _ => Err(format!("unknown command `{command}`, /help lists them")),
+    })
+}
+
+/// The text `/help` shows
+pub fn help() -> String {
+    let width = COMMANDS
+        .iter()
+        .map(|(usage, _)| usage.len())
+        .max()
+        .unwrap_or(0);
+    COMMANDS
+        .iter()
+        .map(|(usage, description)| format!("{usage:<width$}  {description}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub enum Completion {
+    /// the input with the only candidate filled in
+    Done(String),
+    Candidates(Vec<String>),
+}
+
+fn completion(prefix: &str, candidates: Vec<String>) -> Option<Completion> {
+    match candidates.len() {
+        0 => None,
+        1 => Some(Completion::Done(format!("{prefix}{} ", candidates[0]))),
+        _ => Some(Completion::Candidates(candidates)),
+    }
+}
+
+/// Complete the command name, or the engine of `/engine`
+pub fn complete(input: &str) -> Option<Completion> {
+    if let Some(engine) = input.strip_prefix("/engine ") {
+        let engine = engine.trim_start();
+        let candidates = ENGINES
+            .iter()
+            .filter(|candidate| candidate.starts_with(engine))
+            .map(|candidate| candidate.to_string())
+            .collect();
+        return completion("/engine ", candidates);
+    }
+    if !input.starts_with('/') || input.contains(char::is_whitespace) {
+        return None;
+    }
+    let candidates = COMMANDS
+        .iter()
+        .map(|(usage, _)| name(usage))
+        .filter(|command| command.starts_with(input))
+        .map(|command| command.to_string())
+        .collect();
+    completion("", candidates)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SavedMessage {
+    role: String,
+    message: String,
+}
+
+/// What `/save` writes, into a file that doesn't exist yet
+pub fn save(path: &Path, history: &[(Role, String)]) -> anyhow::Result<()> {
+    let saved: Vec<SavedMessage> = history
+        .iter()
+        .map(|(role, message)| SavedMessage {
+            role: role.to_string().to_lowercase(),
+            message: message.clone(),
+        })
+        .collect();
+    let mut file = match std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(path)
+    {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            anyhow::bail!("{} exists, pick another name", path.display())
+        }
+        file => file?,
+    };
+    file.write_all(serde_json::to_string_pretty(&saved)?.as_bytes())?;
+    Ok(())
+}
+
+/// What `/load` reads
+pub fn load(path: &Path) -> anyhow::Result<Vec<(Role, String)>> {
+    let saved: Vec<SavedMessage> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    saved
+        .into_iter()
+        .map(|SavedMessage { role, message }| {
+            let role = match role.as_str() {
+                "user" => Role::User,
+                "assistant" => Role::Assistant,
+                "tool" => Role::Tool,
+                _ => anyhow::bail!("unknown role `{role}`"),
+            };
+            Ok((role, message))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_not_commands() {
+        assert_eq!(parse("hello"), None);
+        assert_eq!(parse("/"), None);
+        assert_eq!(parse("// a comment"), None);
+        assert_eq!(parse("1/2"), None);
+    }
+
+    #[test]
+    fn commands_are_parsed_with_their_argument() {
+        assert_eq!(parse(" /clear "), Some(Ok(Command::Clear)));
+        assert_eq!(
+            parse("/save  chat.json"),
+            Some(Ok(Command::Save("chat.json".into())))
+        );
+        assert_eq!(
+            parse("/engine rhai"),
+            Some(Ok(Command::Engine("rhai".to_string())))
+        );
+        assert_eq!(parse("/temp 0.5"), Some(Ok(Command::Temp(0.5))));
+        assert_eq!(
+            parse("/run let x = 1"),
+            Some(Ok(Command::Run("let x = 1".to_string())))
+        );
+    }
+
+    #[test]
+    fn bad_arguments_are_reported() {
+        assert_eq!(parse("/save"), Some(Err("usage: /save <file>".to_string())));
+        assert!(matches!(parse("/engine python"), Some(Err(err)) if err.contains("python")));
+        assert!(matches!(parse("/temp 3"), Some(Err(_))));
+        assert!(matches!(parse("/temp hot"), Some(Err(_))));
+        assert!(matches!(parse("/nope"), Some(Err(err)) if err.contains("/nope")));
+    }
+
+    #[test]
+    fn completes_commands_and_engines() {
+        assert!(matches!(complete("/cl"), Some(Completion::Done(done)) if done == "/clear "));
+        assert!(
+            matches!(complete("/engine r"), Some(Completion::Done(done)) if done == "/engine rhai ")
+        );
+        match complete("/s") {
+            Some(Completion::Candidates(candidates)) => {
+                assert_eq!(candidates, ["/save", "/system"])
+            }
+            _ => panic!("expected candidates"),
+        }
+        assert!(complete("/x").is_none());
+        assert!(complete("hello").is_none());
+        assert!(complete("/save a").is_none());
+    }
+
+    #[test]
+    fn save_does_not_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.json");
+        let history = vec![
+            (Role::User, "hi".to_string()),
+            (Role::Assistant, "hello".to_string()),
+        ];
+        save(&path, &history).unwrap();
+        assert!(save(&path, &[]).is_err());
+        assert_eq!(load(&path).unwrap(), history);
+    }
+}
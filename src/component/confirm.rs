@@ -0,0 +1,138 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Text},
+    widgets::{Block, Clear, Paragraph, Wrap},
+    Frame,
+};
+use tui_textarea::TextArea;
+
+use crate::tool_env::{approval::Approval, ToolCall};
+
+/// Modal shown while a script waits for the user to approve a tool call.
+pub struct ConfirmComponent {
+    request: Option<(String, ToolCall)>,
+    editor: Option<TextArea<'static>>,
+    error: Option<String>,
+}
+
+impl ConfirmComponent {
+    pub fn new() -> Self {
+        Self {
+            request: None,
+            editor: None,
+            error: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.request.is_some()
+    }
+
+    pub fn open(&mut self, code: String, call: ToolCall) {
+        self.request = Some((code, call));
+        self.editor = None;
+        self.error = None;
+    }
+
+    fn close(&mut self, approval: Approval) -> Option<Approval> {
+        self.request = None;
+        self.editor = None;
+        self.error = None;
+        Some(approval)
+    }
+
+    pub fn render(&mut self, frame: &mut Frame, area: Rect)
+    where
+        Self: Sized,
+    {
+        let Some((code, call)) = &self.request else {
+            return;
+        };
+
+        let area = super::popup_area(area, 80, 70);
+        frame.render_widget(Clear, area);
+        let block = Block::bordered()
+            .title("Confirm tool call")
+            .border_style(Style::new().yellow());
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let vertical = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(if self.editor.is_some() { 8 } else { 3 }),
+            Constraint::Length(1),
+        ]);
+        let [code_area, call_area, help_area] = vertical.areas(inner);
+
+        let code = Paragraph::new(Text::raw(code.as_str()))
+            .block(Block::bordered().title("Script"))
+            .wrap(Wrap { trim: false });
+        frame.render_widget(code, code_area);
+
+        match &mut self.editor {
+            Some(editor) => {
+                editor.set_block(Block::bordered().title(format!("{} arguments", call.name)));
+                frame.render_widget(editor.widget(), call_area);
+            }
+            None => {
+                let args = call
+                    .args
+                    .iter()
+                    .map(|arg| arg.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let call = Paragraph::new(format!("{}({args})", call.name))
+                    .block(Block::bordered().title("Call"))
+                    .wrap(Wrap { trim: false });
+                frame.render_widget(call, call_area);
+            }
+        }
+
+        let help = match (&self.error, &self.editor) {
+            (Some(err), _) => Line::styled(err.as_str(), Style::new().fg(Color::Red)),
+            (None, Some(_)) => Line::raw("[Ctrl+S] run with these arguments  [Esc] back"),
+            (None, None) => Line::raw("[y] approve  [n] deny  [e] edit arguments"),
+        };
+        frame.render_widget(Paragraph::new(help), help_area);
+    }
+
+    /// Returns the user's answer once there is one.
+    pub fn handler_key(&mut self, key: KeyEvent) -> Option<Approval> {
+        let (_, call) = self.request.as_ref()?;
+
+        if let Some(editor) = &mut self.editor {
+            match key.code {
+                KeyCode::Esc => {
+                    self.editor = None;
+                    self.error = None;
+                }
+                KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    let args = editor.lines().join("\n");
+                    match serde_json::from_str::<Vec<serde_json::Value>>(&args) {
+                        Ok(args) => return self.close(Approval::Edit(args)),
+                        Err(err) => {
+                            self.error = Some(format!("arguments must be a JSON array: {err}"))
+                        }
+                    }
+                }
+                _ => {
+                    editor.input(key);
+                }
+            }
+            return None;
+        }
+
+        match key.code {
+            KeyCode::Char('y') | KeyCode::Enter => self.close(Approval::Approve),
+            KeyCode::Char('n') | KeyCode::Esc => self.close(Approval::Deny),
+            KeyCode::Char('e') => {
+                let args = serde_json::to_string_pretty(&call.args).unwrap_or_default();
+                self.editor = Some(TextArea::new(args.lines().map(str::to_string).collect()));
+                None
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Editing the draft in `$VISUAL` or `$EDITOR`, with the TUI suspended meanwhile.
+
+use std::{
+    io::Write,
+    process::Command,
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+use crossterm::{
+    event::{DisableMouseCapture, EnableMouseCapture},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+
+/// How long the input thread waits for an event before it looks at [`INPUT_GATE`]
+pub const INPUT_POLL: Duration = Duration::from_millis(50);
+
+/// Keeps the input thread from reading the editor's keys
+pub static INPUT_GATE: InputGate = InputGate::new();
+
+struct GateState {
+    /// the editor wants the terminal
+    paused: bool,
+    /// the input thread is out of its poll and waits for the editor
+    idle: bool,
+}
+
+/// The editor asks the input thread to stop and waits until it says it has,
+/// the input thread waits until the editor is done.
+pub struct InputGate {
+    state: Mutex<GateState>,
+    changed: Condvar,
+}
+
+impl InputGate {
+    const fn new() -> Self {
+        InputGate {
+            state: Mutex::new(GateState {
+                paused: false,
+                idle: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Called by the input thread between polls, blocks while the editor runs
+    pub fn wait_resumed(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.paused {
+            return;
+        }
+        state.idle = true;
+        self.changed.notify_all();
+        let mut state = self
+            .changed
+            .wait_while(state, |state| state.paused)
+            .unwrap();
+        state.idle = false;
+    }
+
+    /// Blocks until the input thread stopped reading, it goes on when the guard is dropped
+    fn pause(&self) -> anyhow::Result<Paused<'_>> {
+        let mut state = self.state.lock().unwrap();
+        state.paused = true;
+        let paused = Paused(self);
+        let (state, timeout) = self
+            .changed
+            .wait_timeout_while(state, INPUT_POLL * 10, |state| !state.idle)
+            .unwrap();
+        drop(state);
+        anyhow::ensure!(!timeout.timed_out(), "the input thread doesn't answer");
+        Ok(paused)
+    }
+}
+
+struct Paused<'a>(&'a InputGate);
+
+impl Drop for Paused<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().paused = false;
+        self.0.changed.notify_all();
+    }
+}
+
+/// The terminal is given back to the shell, and taken again when dropped
+struct Suspended;
+
+impl Suspended {
+    fn new() -> anyhow::Result<Self> {
+        // restores whatever was done when a step fails
+        let suspended = Suspended;
+        disable_raw_mode()?;
+        execute!(std::io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
+        Ok(suspended)
+    }
+}
+
+impl Drop for Suspended {
+    fn drop(&mut self) {
+        let restored = execute!(std::io::stdout(), EnterAlternateScreen, EnableMouseCapture)
+            .and_then(|()| enable_raw_mode());
+        if let Err(err) = restored {
+            log::error!("restore the terminal error: {err}");
+        }
+    }
+}
+
+fn run_editor(path: &std::path::Path) -> anyhow::Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // `EDITOR="code --wait"` is common
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+    let status = Command::new(program).args(words).arg(path).status()?;
+    anyhow::ensure!(status.success(), "`{editor}` exited with {status}");
+    Ok(())
+}
+
+/// The draft after the user edited it. The caller redraws the whole screen after.
+pub fn edit(draft: &str) -> anyhow::Result<String> {
+    // created only for this user under a random name, removed when dropped
+    let mut file = tempfile::Builder::new()
+        .prefix("script-llama-")
+        .suffix(".md")
+        .tempfile()?;
+    file.write_all(draft.as_bytes())?;
+    file.flush()?;
+
+    {
+        let _paused = INPUT_GATE.pause()?;
+        let _suspended = Suspended::new()?;
+        run_editor(file.path())?;
+    }
+
+    // the editor may have replaced the file, read it by its path
+    let text = std::fs::read_to_string(file.path())?;
+    // editors end the file with a newline
+    Ok(text.strip_suffix('\n').unwrap_or(&text).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn the_input_thread_stops_until_the_editor_is_done() {
+        static GATE: InputGate = InputGate::new();
+        static POLLS: AtomicUsize = AtomicUsize::new(0);
+        std::thread::spawn(|| loop {
+            GATE.wait_resumed();
+            POLLS.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(1));
+        });
+
+        let paused = GATE.pause().unwrap();
+        let polls = POLLS.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(POLLS.load(Ordering::SeqCst), polls);
+
+        drop(paused);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(POLLS.load(Ordering::SeqCst) > polls);
+    }
+
+    #[test]
+    fn pausing_fails_without_an_input_thread() {
+        let gate = InputGate::new();
+        assert!(gate.pause().is_err());
+        assert!(!gate.state.lock().unwrap().paused);
+    }
+}
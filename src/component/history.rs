@@ -0,0 +1,61 @@
+//! What the user sent before, kept in a file next to the project.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Inputs read back at startup
+const MAX_ENTRIES: usize = 1000;
+
+/// Shared by all the conversations, the newest input last.
+pub struct InputHistory {
+    entries: Vec<String>,
+    path: PathBuf,
+}
+
+impl InputHistory {
+    /// The history of the project at `project_path`, empty when there is none yet
+    pub fn load(project_path: &Path) -> Self {
+        let path = project_path.with_extension("history");
+        // one JSON string per line, the inputs may span lines
+        let mut entries: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        let skip = entries.len().saturating_sub(MAX_ENTRIES);
+        entries.drain(..skip);
+        InputHistory { entries, path }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, i: usize) -> Option<&str> {
+        self.entries.get(i).map(String::as_str)
+    }
+
+    pub fn push(&mut self, input: &str) {
+        if input.trim().is_empty() || self.entries.last().is_some_and(|last| last == input) {
+            return;
+        }
+        self.entries.push(input.to_string());
+        if let Err(err) = self.append(input) {
+            log::warn!("input history `{}`: {err}", self.path.display());
+        }
+    }
+
+    fn append(&self, input: &str) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_string(input)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    text::{Line, Span, Text},
+    widgets::{Block, Paragraph},
+    Frame,
+};
+
+pub struct InspectorComponent {
+    variables: Vec<(String, String)>,
+    pub visible: bool,
+}
+
+impl InspectorComponent {
+    pub fn new() -> Self {
+        Self {
+            variables: Vec::new(),
+            visible: false,
+        }
+    }
+
+    pub fn set_variables(&mut self, variables: Vec<(String, String)>) {
+        self.variables = variables;
+    }
+
+    pub fn render(&mut self, frame: &mut Frame, area: Rect)
+    where
+        Self: Sized,
+    {
+        let text: Text = self
+            .variables
+            .iter()
+            .map(|(name, value)| {
+                Line::from(vec![
+                    Span::styled(name.as_str(), Style::new().fg(Color::Cyan)),
+                    Span::raw(" = "),
+                    Span::raw(value.as_str()),
+                ])
+            })
+            .collect::<Vec<_>>()
+            .into();
+
+        let paragraph = Paragraph::new(text).block(Block::bordered().title("Variables"));
+        frame.render_widget(paragraph, area);
+    }
+}
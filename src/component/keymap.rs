@@ -0,0 +1,394 @@
+//! The keys bound to the named actions, with the defaults overridden by a TOML file:
+//!
+//! ```toml
+//! send = "ctrl+s"
+//! quit = ["esc", "ctrl+q"]
+//! ```
+
+use std::{collections::HashMap, fmt, path::Path};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Help,
+    Quit,
+    SwitchTab,
+    Send,
+    Editor,
+    ClearScreen,
+    ResetScript,
+    Inspector,
+    FocusMessages,
+    NewChat,
+    RenameChat,
+    DeleteChat,
+    PrevChat,
+    NextChat,
+}
+
+impl Action {
+    /// The name in the keymap file
+    fn name(self) -> &'static str {
+        match self {
+            Action::Help => "help",
+            Action::Quit => "quit",
+            Action::SwitchTab => "switch_tab",
+            Action::Send => "send",
+            Action::Editor => "editor",
+            Action::ClearScreen => "clear_screen",
+            Action::ResetScript => "reset_script",
+            Action::Inspector => "inspector",
+            Action::FocusMessages => "focus_messages",
+            Action::NewChat => "new_chat",
+            Action::RenameChat => "rename_chat",
+            Action::DeleteChat => "delete_chat",
+            Action::PrevChat => "prev_chat",
+            Action::NextChat => "next_chat",
+        }
+    }
+}
+
+/// Keys the message view handles itself, an action bound to them would take them over
+const RESERVED: [(&str, &str); 8] = [
+    ("up", "scroll up"),
+    ("k", "scroll up"),
+    ("down", "scroll down"),
+    ("j", "scroll down"),
+    ("g", "scroll to the top"),
+    ("G", "scroll to the bottom"),
+    ("tab", "select the next card"),
+    ("enter", "open the selected card"),
+];
+
+/// `(action, default keys, what it does)`, in the order of the help overlay
+const ACTIONS: [(Action, &[&str], &str); 14] = [
+    (Action::Help, &["f1", "?"], "show the key bindings"),
+    (Action::Quit, &["esc", "ctrl+q"], "quit, after confirming"),
+    (
+        Action::SwitchTab,
+        &["f2"],
+        "switch between the chat and the settings",
+    ),
+    (Action::Send, &["ctrl+s"], "send the input"),
+    (Action::Editor, &["ctrl+g"], "edit the input in $EDITOR"),
+    (Action::ClearScreen, &["f5"], "redraw the screen"),
+    (Action::ResetScript, &["f6"], "reset the script engine"),
+    (Action::Inspector, &["f7"], "show the script variables"),
+    (
+        Action::FocusMessages,
+        &["f9"],
+        "scroll and select the messages",
+    ),
+    (Action::NewChat, &["f3"], "new conversation"),
+    (Action::RenameChat, &["f4"], "rename the conversation"),
+    (
+        Action::DeleteChat,
+        &["f8"],
+        "delete the conversation, pressed twice",
+    ),
+    (Action::PrevChat, &["alt+up"], "previous conversation"),
+    (Action::NextChat, &["alt+down"], "next conversation"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Key {
+    /// A character typed in a text box rather than a shortcut
+    fn is_text(&self) -> bool {
+        matches!(self.code, KeyCode::Char(_))
+            && self.modifiers.difference(KeyModifiers::SHIFT).is_empty()
+    }
+
+    fn matches(&self, key: &KeyEvent) -> bool {
+        match key.code {
+            // the shift of `?` is in the char already, terminals differ in reporting it
+            KeyCode::Char(_) => {
+                key.code == self.code
+                    && key.modifiers.difference(KeyModifiers::SHIFT)
+                        == self.modifiers.difference(KeyModifiers::SHIFT)
+            }
+            _ => key.code == self.code && key.modifiers == self.modifiers,
+        }
+    }
+
+    /// Both keys are pressed the same way
+    fn same(&self, other: &Key) -> bool {
+        self.matches(&KeyEvent::new(other.code, other.modifiers))
+    }
+}
+
+impl std::str::FromStr for Key {
+    type Err = anyhow::Error;
+
+    /// `ctrl+s`, `alt+up`, `f1`, `?`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = s;
+        // `+` alone, or `ctrl++`, is the key itself
+        while let Some((modifier, key)) = rest.split_once('+').filter(|(_, key)| !key.is_empty()) {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => anyhow::bail!("unknown modifier `{modifier}` in `{s}`"),
+            };
+            rest = key;
+        }
+        let code = match rest.to_lowercase().as_str() {
+            "esc" => KeyCode::Esc,
+            "enter" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "backspace" => KeyCode::Backspace,
+            "delete" => KeyCode::Delete,
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            f if f.len() > 1 && f.starts_with('f') => match f[1..].parse() {
+                Ok(n @ 1..=12) => KeyCode::F(n),
+                _ => anyhow::bail!("unknown key `{rest}` in `{s}`"),
+            },
+            _ => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => anyhow::bail!("unknown key `{rest}` in `{s}`"),
+                }
+            }
+        };
+        Ok(Key { code, modifiers })
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl+"),
+            (KeyModifiers::ALT, "Alt+"),
+            (KeyModifiers::SHIFT, "Shift+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                f.write_str(name)?;
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => f.write_str("Space"),
+            KeyCode::Char(c) if self.modifiers.is_empty() => write!(f, "{c}"),
+            KeyCode::Char(c) => write!(f, "{}", c.to_ascii_uppercase()),
+            KeyCode::F(n) => write!(f, "F{n}"),
+            KeyCode::PageUp => f.write_str("PgUp"),
+            KeyCode::PageDown => f.write_str("PgDn"),
+            code => write!(f, "{code:?}"),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Keys {
+    One(String),
+    Many(Vec<String>),
+}
+
+pub struct Keymap {
+    bindings: Vec<(Action, Vec<Key>)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = ACTIONS
+            .iter()
+            .map(|(action, keys, _)| {
+                let keys = keys.iter().map(|key| key.parse().unwrap()).collect();
+                (*action, keys)
+            })
+            .collect();
+        Keymap { bindings }
+    }
+}
+
+impl Keymap {
+    /// The defaults, with the actions in the file at `path` bound to its keys instead
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("keymap `{}`: {e}", path.display()))?;
+        let overrides: HashMap<Action, Keys> = toml::from_str(&text)
+            .map_err(|e| anyhow::anyhow!("keymap `{}`: {e}", path.display()))?;
+        let mut keymap = Keymap::default();
+        for (action, keys) in overrides {
+            let keys = match keys {
+                Keys::One(key) => vec![key],
+                Keys::Many(keys) => keys,
+            };
+            let keys = keys
+                .iter()
+                .map(|key| key.parse())
+                .collect::<anyhow::Result<Vec<Key>>>()
+                .map_err(|e| anyhow::anyhow!("keymap `{}`: {e}", path.display()))?;
+            if let Some((_, bound)) = keymap.bindings.iter_mut().find(|(a, _)| *a == action) {
+                *bound = keys;
+            }
+        }
+        keymap
+            .check()
+            .map_err(|e| anyhow::anyhow!("keymap `{}`: {e}", path.display()))?;
+        Ok(keymap)
+    }
+
+    /// Every key does one thing: no two actions share it, and none takes a key of the message view
+    fn check(&self) -> anyhow::Result<()> {
+        for (i, (action, keys)) in self.bindings.iter().enumerate() {
+            for key in keys {
+                for (other, other_keys) in &self.bindings[i + 1..] {
+                    if other_keys.iter().any(|other_key| other_key.same(key)) {
+                        anyhow::bail!(
+                            "`{key}` is bound to both {} and {}",
+                            action.name(),
+                            other.name()
+                        );
+                    }
+                }
+                for (reserved, what) in RESERVED {
+                    if key.same(&reserved.parse()?) {
+                        anyhow::bail!(
+                            "`{key}` is bound to {}, the message view uses it to {what}",
+                            action.name()
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The action bound to `key`. Plain characters are text while `typing`, not actions.
+    pub fn action(&self, key: &KeyEvent, typing: bool) -> Option<Action> {
+        self.bindings.iter().find_map(|(action, keys)| {
+            keys.iter()
+                .any(|bound| bound.matches(key) && !(typing && bound.is_text()))
+                .then_some(*action)
+        })
+    }
+
+    /// `F1/?`, what the help bar and the overlay show for `action`
+    pub fn keys(&self, action: Action) -> String {
+        self.bindings
+            .iter()
+            .find(|(a, _)| *a == action)
+            .map(|(_, keys)| {
+                keys.iter()
+                    .map(|key| key.to_string())
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+            .unwrap_or_default()
+    }
+
+    /// `(keys, what it does)` of every action, for the help overlay
+    pub fn help(&self) -> Vec<(String, &'static str)> {
+        ACTIONS
+            .iter()
+            .map(|(action, _, description)| (self.keys(*action), *description))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> Key {
+        Key { code, modifiers }
+    }
+
+    #[test]
+    fn keys_are_parsed() {
+        let parse = |s: &str| s.parse::<Key>().unwrap();
+        assert_eq!(parse("f1"), key(KeyCode::F(1), KeyModifiers::NONE));
+        assert_eq!(parse("?"), key(KeyCode::Char('?'), KeyModifiers::NONE));
+        assert_eq!(
+            parse("Ctrl+S"),
+            key(KeyCode::Char('s'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(
+            parse("ctrl+alt+up"),
+            key(KeyCode::Up, KeyModifiers::CONTROL | KeyModifiers::ALT)
+        );
+        assert_eq!(parse("space"), key(KeyCode::Char(' '), KeyModifiers::NONE));
+        assert_eq!(parse("+"), key(KeyCode::Char('+'), KeyModifiers::NONE));
+        assert_eq!(
+            parse("ctrl++"),
+            key(KeyCode::Char('+'), KeyModifiers::CONTROL)
+        );
+        assert_eq!(
+            parse("pagedown"),
+            key(KeyCode::PageDown, KeyModifiers::NONE)
+        );
+    }
+
+    #[test]
+    fn bad_keys_are_refused() {
+        for bad in ["", "f0", "f13", "hyper+a", "ctrl+", "ab", "ctrl+esc+x"] {
+            assert!(bad.parse::<Key>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn keys_are_shown_as_parsed() {
+        for (s, shown) in [("ctrl+s", "Ctrl+S"), ("alt+down", "Alt+Down"), ("?", "?")] {
+            assert_eq!(s.parse::<Key>().unwrap().to_string(), shown);
+        }
+    }
+
+    fn load(text: &str) -> anyhow::Result<Keymap> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keymap.toml");
+        std::fs::write(&path, text).unwrap();
+        Keymap::load(&path)
+    }
+
+    #[test]
+    fn the_defaults_do_not_conflict() {
+        Keymap::default().check().unwrap();
+        load(include_str!("../../static/keymap.toml")).unwrap();
+    }
+
+    #[test]
+    fn a_key_bound_twice_is_refused() {
+        let err = load(r#"send = "f1""#).unwrap_err().to_string();
+        assert!(err.contains("`F1` is bound to both help and send"), "{err}");
+
+        let err = load(r#"new_chat = "ctrl+g""#).unwrap_err().to_string();
+        assert!(err.contains("editor and new_chat"), "{err}");
+
+        load(
+            r#"send = "f1"
+help = "f10""#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn keys_of_the_message_view_are_refused() {
+        let err = load(r#"send = "enter""#).unwrap_err().to_string();
+        assert!(
+            err.contains("bound to send, the message view uses it"),
+            "{err}"
+        );
+        assert!(load(r#"inspector = "j""#).is_err());
+        assert!(load(r#"inspector = "G""#).is_err());
+        load(r#"inspector = "ctrl+j""#).unwrap();
+    }
+}
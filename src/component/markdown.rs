@@ -0,0 +1,302 @@
+//! Turns messages into styled lines: Markdown for the text, syntax highlighting for code.
+
+use std::sync::LazyLock;
+
+use pulldown_cmark::{CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+use ratatui::{
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{FontStyle, Theme, ThemeSet},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+use crate::tool_env::protocol::{Action, Protocol, Reply};
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEME: LazyLock<Theme> = LazyLock::new(|| {
+    ThemeSet::load_defaults()
+        .themes
+        .remove("base16-ocean.dark")
+        .unwrap_or_default()
+});
+
+const CODE_BG: Color = Color::Rgb(43, 48, 59);
+
+/// How assistant messages split into the reply and the script.
+#[derive(Debug, Clone)]
+pub struct MessageFormat {
+    pub protocol: Protocol,
+    /// the language of the script engine, `none` without one
+    pub language: String,
+}
+
+impl MessageFormat {
+    /// The text for the user and the script of an assistant message
+    pub fn reply(&self, message: &str) -> Reply {
+        if self.language == "none" {
+            return Reply {
+                text: message.to_string(),
+                action: None,
+            };
+        }
+        self.protocol.split(message, &self.language)
+    }
+
+    /// The script, or the call as JSON
+    pub fn action(&self, action: &Action) -> Vec<Line<'static>> {
+        match action {
+            Action::Script(code) => highlight(code, &self.language),
+            Action::Call { name, arguments } => {
+                let call = serde_json::json!({ "name": name, "arguments": arguments });
+                let call = serde_json::to_string_pretty(&call).unwrap_or_default();
+                highlight(&call, "json")
+            }
+        }
+    }
+}
+
+/// Tool results are JSON, pretty-printed when they parse
+pub fn tool(message: &str) -> Vec<Line<'static>> {
+    match serde_json::from_str::<serde_json::Value>(message) {
+        Ok(value) => highlight(
+            &serde_json::to_string_pretty(&value).unwrap_or_default(),
+            "json",
+        ),
+        Err(_) => message
+            .lines()
+            .map(|line| Line::raw(line.to_string()))
+            .collect(),
+    }
+}
+
+/// Rhai has no syntax of its own here, Rust is the closest
+fn find_syntax(lang: &str) -> Option<&'static SyntaxReference> {
+    let lang = match lang.to_ascii_lowercase().as_str() {
+        "rhai" => "rs".to_string(),
+        lang => lang.to_string(),
+    };
+    SYNTAXES.find_syntax_by_token(&lang)
+}
+
+/// `code` in `lang`, plain when the language is unknown
+pub fn highlight(code: &str, lang: &str) -> Vec<Line<'static>> {
+    let code_style = Style::new().bg(CODE_BG);
+    let Some(syntax) = find_syntax(lang) else {
+        return code
+            .lines()
+            .map(|line| Line::styled(line.to_string(), code_style))
+            .collect();
+    };
+
+    let mut highlighter = HighlightLines::new(syntax, &THEME);
+    LinesWithEndings::from(code)
+        .map(|line| {
+            let Ok(ranges) = highlighter.highlight_line(line, &SYNTAXES) else {
+                return Line::styled(line.trim_end().to_string(), code_style);
+            };
+            let spans: Vec<Span> = ranges
+                .into_iter()
+                .map(|(style, text)| {
+                    let fg = style.foreground;
+                    let mut span_style = code_style.fg(Color::Rgb(fg.r, fg.g, fg.b));
+                    if style.font_style.contains(FontStyle::BOLD) {
+                        span_style = span_style.bold();
+                    }
+                    if style.font_style.contains(FontStyle::ITALIC) {
+                        span_style = span_style.italic();
+                    }
+                    Span::styled(text.trim_end_matches(['\n', '\r']).to_string(), span_style)
+                })
+                .collect();
+            Line::from(spans).style(code_style)
+        })
+        .collect()
+}
+
+/// Where a new line starts inside lists and quotes
+#[derive(Default)]
+struct Prefix {
+    /// the next number of each open list, `None` for bullets
+    lists: Vec<Option<u64>>,
+    quote: usize,
+}
+
+impl Prefix {
+    fn indent(&self) -> String {
+        format!(
+            "{}{}",
+            "│ ".repeat(self.quote),
+            "  ".repeat(self.lists.len().saturating_sub(1))
+        )
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    lines: Vec<Line<'static>>,
+    line: Vec<Span<'static>>,
+    styles: Vec<Style>,
+    prefix: Prefix,
+    /// the language and the text of the fenced block being read
+    code: Option<(String, String)>,
+}
+
+impl Writer {
+    fn style(&self) -> Style {
+        self.styles.last().copied().unwrap_or_default()
+    }
+
+    fn push_style(&mut self, f: impl FnOnce(Style) -> Style) {
+        self.styles.push(f(self.style()));
+    }
+
+    fn text(&mut self, text: String) {
+        self.styled(text, self.style());
+    }
+
+    fn styled(&mut self, text: String, style: Style) {
+        if self.line.is_empty() {
+            let indent = self.prefix.indent();
+            if !indent.is_empty() {
+                self.line.push(Span::raw(indent));
+            }
+        }
+        self.line.push(Span::styled(text, style));
+    }
+
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            self.lines.push(Line::from(std::mem::take(&mut self.line)));
+        }
+    }
+
+    /// An empty line between blocks, but not at the start or twice
+    fn gap(&mut self) {
+        self.flush();
+        if self.prefix.lists.is_empty() && self.lines.last().is_some_and(|line| line.width() > 0) {
+            self.lines.push(Line::default());
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Heading { level, .. } => {
+                self.gap();
+                let color = match level {
+                    HeadingLevel::H1 => Color::Magenta,
+                    HeadingLevel::H2 => Color::Cyan,
+                    _ => Color::Blue,
+                };
+                self.push_style(|style| style.fg(color).bold());
+                self.text(format!("{} ", "#".repeat(level as usize)));
+            }
+            Tag::Paragraph => {
+                if self.prefix.lists.is_empty() {
+                    self.gap();
+                }
+            }
+            Tag::BlockQuote(_) => {
+                self.gap();
+                self.prefix.quote += 1;
+                self.push_style(|style| style.italic());
+            }
+            Tag::CodeBlock(kind) => {
+                self.gap();
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or("").to_string()
+                    }
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.code = Some((lang, String::new()));
+            }
+            Tag::List(start) => {
+                if self.prefix.lists.is_empty() {
+                    self.gap();
+                } else {
+                    self.flush();
+                }
+                self.prefix.lists.push(start);
+            }
+            Tag::Item => {
+                self.flush();
+                let bullet = match self.prefix.lists.last_mut() {
+                    Some(Some(n)) => {
+                        *n += 1;
+                        format!("{}. ", *n - 1)
+                    }
+                    _ => "• ".to_string(),
+                };
+                self.styled(bullet, Style::new());
+            }
+            Tag::Emphasis => self.push_style(|style| style.italic()),
+            Tag::Strong => self.push_style(|style| style.bold()),
+            Tag::Strikethrough => self.push_style(|style| style.crossed_out()),
+            Tag::Link { .. } => self.push_style(|style| style.fg(Color::Blue).underlined()),
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Heading(_) => {
+                self.styles.pop();
+                self.flush();
+            }
+            TagEnd::BlockQuote => {
+                self.styles.pop();
+                self.flush();
+                self.prefix.quote -= 1;
+            }
+            TagEnd::Paragraph | TagEnd::Item => self.flush(),
+            TagEnd::CodeBlock => {
+                if let Some((lang, code)) = self.code.take() {
+                    self.lines.extend(highlight(code.trim_end(), &lang));
+                }
+            }
+            TagEnd::List(_) => {
+                self.flush();
+                self.prefix.lists.pop();
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough | TagEnd::Link => {
+                self.styles.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The lines of `text` read as Markdown
+pub fn markdown(text: &str) -> Vec<Line<'static>> {
+    let mut writer = Writer::default();
+    for event in Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH) {
+        match event {
+            Event::Start(tag) => writer.start(tag),
+            Event::End(tag) => writer.end(tag),
+            Event::Text(text) => match &mut writer.code {
+                Some((_, code)) => code.push_str(&text),
+                None => writer.text(text.into_string()),
+            },
+            Event::Code(code) => {
+                let style = writer.style().fg(Color::Yellow).bg(CODE_BG);
+                writer.styled(code.into_string(), style);
+            }
+            Event::SoftBreak | Event::HardBreak => writer.flush(),
+            Event::Rule => {
+                writer.gap();
+                writer.lines.push(Line::styled(
+                    "─".repeat(40),
+                    Style::new().fg(Color::DarkGray),
+                ));
+            }
+            Event::Html(html) | Event::InlineHtml(html) => writer.text(html.into_string()),
+            _ => {}
+        }
+    }
+    writer.flush();
+    writer.lines
+}
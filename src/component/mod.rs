@@ -1,29 +1,123 @@
+use std::{
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use crossbeam::channel::{Receiver, RecvError};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture},
+    cursor,
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
-    backend::CrosstermBackend,
-    layout::{Constraint, Layout},
-    widgets::{Block, Paragraph, Tabs},
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Flex, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Clear, Paragraph, Tabs},
     Frame, Terminal,
 };
 
-use crate::chat::im_channel::{Message, MessageRx, MessageTx, Role};
+use crate::{
+    chat::{
+        im_channel::{Control, Message, MessageRx, MessageTx, Role},
+        settings::Settings,
+    },
+    llm::local_llm::Token,
+    tool_env::approval::Approval,
+};
+use keymap::{Action, Keymap};
 
+pub mod card;
 pub mod chat;
+pub mod command;
+pub mod confirm;
+pub mod editor;
+pub mod history;
+pub mod inspector;
+pub mod keymap;
+pub mod markdown;
+pub mod sessions;
+pub mod settings;
+
+/// A rect of `percent_x` x `percent_y` in the middle of `area`, for modals.
+pub fn popup_area(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
+    let vertical = Layout::vertical([Constraint::Percentage(percent_y)]).flex(Flex::Center);
+    let horizontal = Layout::horizontal([Constraint::Percentage(percent_x)]).flex(Flex::Center);
+    let [area] = vertical.areas(area);
+    let [area] = horizontal.areas(area);
+    area
+}
+
+const TABS: [&str; 2] = ["Chat", "Setting"];
+
+/// Redraws are at most this often while messages stream in
+const FRAME: Duration = Duration::from_millis(16);
+
+/// Raw mode, the alternate screen and the mouse, given back to the shell when dropped
+struct TerminalMode;
+
+impl TerminalMode {
+    fn enter() -> anyhow::Result<Self> {
+        // restores whatever was done when a step fails
+        let mode = TerminalMode;
+        enable_raw_mode()?;
+        execute!(std::io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
+        Ok(mode)
+    }
+}
+
+impl Drop for TerminalMode {
+    fn drop(&mut self) {
+        let restored = disable_raw_mode().and_then(|()| {
+            execute!(
+                std::io::stdout(),
+                LeaveAlternateScreen,
+                DisableMouseCapture,
+                cursor::Show
+            )
+        });
+        if let Err(err) = restored {
+            log::error!("restore the terminal error: {err}");
+        }
+    }
+}
 
 pub struct App {
-    pub chat: chat::ChatComponent,
+    pub sessions: sessions::SessionsComponent,
+    settings: settings::SettingsComponent,
+    /// index into [`TABS`]
+    tab: usize,
+    keymap: Rc<Keymap>,
+    /// the overlay listing the key bindings is shown
+    help: bool,
+    /// the user asked to quit and is asked to confirm
+    quitting: bool,
     rx: MessageRx,
+    tx: MessageTx,
 }
 
 impl App {
-    pub fn new(rx: MessageRx, tx: MessageTx) -> Self {
+    pub fn new(
+        rx: MessageRx,
+        tx: MessageTx,
+        format: markdown::MessageFormat,
+        history: history::InputHistory,
+        keymap: Keymap,
+        settings: settings::SettingsComponent,
+    ) -> Self {
+        let keymap = Rc::new(keymap);
         Self {
-            chat: chat::ChatComponent::new(Default::default(), tx),
+            sessions: sessions::SessionsComponent::new(format, history, keymap.clone(), tx.clone()),
+            settings,
+            tab: 0,
+            keymap,
+            help: false,
+            quitting: false,
             rx,
+            tx,
         }
     }
 
@@ -36,72 +130,232 @@ impl App {
 
         let [tabs_area, main_area, help_area] = vertical.areas(f.size());
 
-        let tabs = Tabs::new(vec!["Chat", "Setting"])
-            .select(0)
+        let tabs = Tabs::new(TABS.to_vec())
+            .select(self.tab)
             .padding("[", "]")
-            .block(Block::bordered());
+            .block(Block::bordered().title(format!(
+                "[{}] switch tab",
+                self.keymap.keys(Action::SwitchTab)
+            )));
 
         f.render_widget(tabs, tabs_area);
-        self.chat.render(f, main_area);
+        if self.tab == 0 {
+            let horizontal = Layout::horizontal([Constraint::Length(24), Constraint::Min(20)]);
+            let [sidebar_area, chat_area] = horizontal.areas(main_area);
+            self.sessions.render(f, sidebar_area);
+            self.sessions.active().render(f, chat_area);
+        } else {
+            self.settings.render(f, main_area);
+        }
 
-        let help_message = Paragraph::new(format!("help... event:{}", self.chat.event));
+        let help_message = Paragraph::new(format!(
+            "[{}] keys  [{}] quit",
+            self.keymap.keys(Action::Help),
+            self.keymap.keys(Action::Quit)
+        ))
+        .fg(Color::DarkGray);
         f.render_widget(help_message, help_area);
+
+        if self.help {
+            self.render_help(f);
+        }
+        if self.quitting {
+            let area = popup_area(f.size(), 30, 20);
+            f.render_widget(Clear, area);
+            let confirm = Paragraph::new(vec![
+                Line::raw("Quit?"),
+                Line::raw(""),
+                Line::raw("[y/Enter] quit  [any other key] stay"),
+            ])
+            .centered()
+            .block(Block::bordered().title("Quit").yellow());
+            f.render_widget(confirm, area);
+        }
+    }
+
+    /// The active key bindings, over everything else
+    fn render_help(&self, f: &mut Frame) {
+        let help = self.keymap.help();
+        let width = help.iter().map(|(keys, _)| keys.len()).max().unwrap_or(0);
+        let lines: Vec<Line> = help
+            .into_iter()
+            .map(|(keys, description)| {
+                Line::from(vec![
+                    Span::styled(format!("{keys:<width$}  "), Style::new().fg(Color::Cyan)),
+                    Span::raw(description),
+                ])
+            })
+            .collect();
+        let area = popup_area(f.size(), 60, 70);
+        f.render_widget(Clear, area);
+        let block = Block::bordered()
+            .title("Keys")
+            .title_bottom("[any key] close");
+        f.render_widget(Paragraph::new(lines).block(block), area);
     }
 
     pub fn run_loop(mut self) -> anyhow::Result<()> {
         let (input_tx, input_rx) = crossbeam::channel::unbounded();
 
-        // setup terminal
-        enable_raw_mode()?;
-        let mut stdout = std::io::stdout();
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-
-        let backend = CrosstermBackend::new(stdout);
+        // setup terminal, restored however the loop ends
+        let _mode = TerminalMode::enter()?;
+        let backend = CrosstermBackend::new(std::io::stdout());
         let mut terminal = Terminal::new(backend)?;
 
         // create app and run it
         std::thread::spawn(move || Self::listen_user_input(input_tx));
 
-        loop {
+        'frames: loop {
             terminal.draw(|f| self.render(f))?;
 
-            let input = crossbeam::select! {
-                recv(input_rx) -> input =>{
-                    if let Ok(input) = input {
-                        chat::Input::Event(input)
-                    }else{
-                        break;
+            let input = match self.next_input(&input_rx, None) {
+                Ok(Some(input)) => input,
+                Ok(None) => break,
+                Err(err) if err.is::<RecvError>() => break,
+                Err(err) => return Err(err),
+            };
+            if !self.handle_input(&mut terminal, input) {
+                break;
+            }
+            // a reply streams in token by token, take all that comes within a frame
+            // before drawing again
+            let deadline = Instant::now() + FRAME;
+            loop {
+                match self.next_input(&input_rx, Some(deadline)) {
+                    Ok(Some(input)) => {
+                        if !self.handle_input(&mut terminal, input) {
+                            break 'frames;
+                        }
                     }
+                    Ok(None) => break,
+                    Err(err) if err.is::<RecvError>() => break 'frames,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The next input, `None` when nothing came before `deadline`.
+    /// A closed channel is a [`RecvError`].
+    fn next_input(
+        &self,
+        input_rx: &Receiver<std::io::Result<Event>>,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Option<chat::Input>> {
+        let timeout = deadline.map_or_else(crossbeam::channel::never, crossbeam::channel::at);
+        crossbeam::select! {
+            recv(input_rx) -> input => {
+                let event = input?.context("read the terminal input")?;
+                Ok(Some(chat::Input::Event(event)))
+            }
+            recv(self.rx) -> message => Ok(Some(chat::Input::Message(message?))),
+            recv(timeout) -> _ => Ok(None),
+        }
+    }
+
+    /// `false` when the user quits
+    fn handle_input<B: Backend>(&mut self, terminal: &mut Terminal<B>, input: chat::Input) -> bool {
+        let action = match &input {
+            chat::Input::Event(Event::Key(key)) => {
+                let typing = self.tab == 0 && self.sessions.active().is_typing();
+                self.keymap.action(key, typing)
+            }
+            _ => None,
+        };
+        match input {
+            chat::Input::Event(Event::Key(key)) if self.quitting => {
+                if matches!(key.code, KeyCode::Char('y') | KeyCode::Enter) {
+                    return false;
+                }
+                self.quitting = false;
+            }
+            // any key closes the overlay
+            chat::Input::Event(Event::Key(_)) if self.help => self.help = false,
+            chat::Input::Event(Event::Key(_)) if action == Some(Action::SwitchTab) => {
+                self.tab = (self.tab + 1) % TABS.len();
+            }
+            chat::Input::Event(Event::Key(key)) if self.tab == 1 => {
+                if key.code == KeyCode::Esc {
+                    self.tab = 0;
+                } else if action == Some(Action::Help) {
+                    self.help = true;
+                } else if action == Some(Action::Quit) {
+                    self.quitting = true;
+                } else if let Some(settings) = self.settings.handler_key(key) {
+                    self.send_settings(settings);
+                }
+            }
+            chat::Input::Message(Message {
+                contont: Token::Control(Control::DefaultEngine(language)),
+                ..
+            }) => self.sessions.set_default_language(&language),
+            chat::Input::Message(Message {
+                contont: Token::Control(Control::SettingsError(err)),
+                ..
+            }) => self.settings.failed(err),
+            chat::Input::Message(message) => {
+                // the approval dialog lives in the chat tab of its conversation
+                if let Token::Control(Control::ConfirmCall { .. }) = &message.contont {
+                    self.tab = 0;
+                    self.sessions.select(message.chat_id);
                 }
-                recv(self.rx) -> message =>{
-                    if let Ok(message) = message {
-                        chat::Input::Message(message)
-                    }else{
-                        break;
+                // the conversation may have been deleted while the model answered it
+                match self.sessions.get(message.chat_id) {
+                    Some(chat) => {
+                        chat.handler_input(terminal, chat::Input::Message(message));
+                    }
+                    // nobody is left to answer, and the executor waits for it
+                    None => {
+                        if let Token::Control(Control::ConfirmCall { .. }) = message.contont {
+                            let _ = self.tx.send(Message {
+                                chat_id: message.chat_id,
+                                role: Role::User,
+                                contont: Token::Control(Control::ConfirmReply(Approval::Deny)),
+                            });
+                        }
                     }
                 }
-            };
-
-            if !self.chat.handler_input(&mut terminal, input) {
-                break;
+            }
+            chat::Input::Event(Event::Key(key)) if self.sessions.handler_key(key) => {}
+            input => {
+                match self.sessions.active().handler_input(terminal, input) {
+                    Some(Action::Help) => self.help = true,
+                    Some(Action::Quit) => self.quitting = true,
+                    _ => {}
+                }
+                // `/temp` changes the settings of every conversation
+                if let Some(temperature) = self.sessions.active().take_temperature() {
+                    let settings = self.settings.set_temperature(temperature);
+                    self.send_settings(settings);
+                }
             }
         }
+        true
+    }
 
-        // restore terminal
-        disable_raw_mode()?;
-        execute!(
-            terminal.backend_mut(),
-            LeaveAlternateScreen,
-            DisableMouseCapture
-        )?;
-        terminal.show_cursor()?;
-        Ok(())
+    fn send_settings(&mut self, settings: Settings) {
+        let _ = self.tx.send(Message {
+            chat_id: self.sessions.active_id(),
+            role: Role::User,
+            contont: Token::Control(Control::Settings(settings)),
+        });
     }
 
-    fn listen_user_input(tx: crossbeam::channel::Sender<event::Event>) {
+    /// Ends after passing on the first error, or when the app is gone
+    fn listen_user_input(tx: crossbeam::channel::Sender<std::io::Result<Event>>) {
         loop {
-            tx.send(event::read().expect("Failed to read event"))
-                .expect("Failed to send input message");
+            // the editor reads the terminal meanwhile
+            editor::INPUT_GATE.wait_resumed();
+            let event = match event::poll(editor::INPUT_POLL) {
+                Ok(true) => event::read(),
+                Ok(false) => continue,
+                Err(err) => Err(err),
+            };
+            let failed = event.is_err();
+            if tx.send(event).is_err() || failed {
+                return;
+            }
         }
     }
 
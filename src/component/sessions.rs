@@ -0,0 +1,229 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{Block, Paragraph},
+    Frame,
+};
+use tui_textarea::{CursorMove, TextArea};
+
+use crate::{
+    chat::im_channel::{ChatId, Control, Message, MessageTx, Role},
+    llm::local_llm::Token,
+};
+
+use super::{
+    chat::ChatComponent,
+    history::InputHistory,
+    keymap::{Action, Keymap},
+    markdown::MessageFormat,
+};
+
+pub struct Session {
+    pub id: ChatId,
+    pub name: String,
+    pub chat: ChatComponent,
+}
+
+/// The conversations, and the sidebar to create, rename, switch and delete them.
+pub struct SessionsComponent {
+    sessions: Vec<Session>,
+    active: usize,
+    next_id: ChatId,
+    /// the new name of the active conversation, while renaming
+    rename: Option<TextArea<'static>>,
+    /// F8 was pressed once, pressing it again deletes the active conversation
+    delete_armed: bool,
+    format: MessageFormat,
+    /// shared by all the conversations
+    history: Rc<RefCell<InputHistory>>,
+    keymap: Rc<Keymap>,
+    tx: MessageTx,
+}
+
+impl SessionsComponent {
+    pub fn new(
+        format: MessageFormat,
+        history: InputHistory,
+        keymap: Rc<Keymap>,
+        tx: MessageTx,
+    ) -> Self {
+        let mut sessions = Self {
+            sessions: Vec::new(),
+            active: 0,
+            next_id: 0,
+            rename: None,
+            delete_armed: false,
+            format,
+            history: Rc::new(RefCell::new(history)),
+            keymap,
+            tx,
+        };
+        sessions.create();
+        sessions
+    }
+
+    fn create(&mut self) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.push(Session {
+            id,
+            name: format!("Chat {}", id + 1),
+            chat: ChatComponent::new(
+                id,
+                Default::default(),
+                self.format.clone(),
+                self.history.clone(),
+                self.keymap.clone(),
+                self.tx.clone(),
+            ),
+        });
+        self.active = self.sessions.len() - 1;
+    }
+
+    fn delete(&mut self) {
+        let session = self.sessions.remove(self.active);
+        let _ = self.tx.send(Message {
+            chat_id: session.id,
+            role: Role::User,
+            contont: Token::Control(Control::DeleteChat),
+        });
+        if self.sessions.is_empty() {
+            self.create();
+        }
+        self.active = self.active.min(self.sessions.len() - 1);
+    }
+
+    pub fn active(&mut self) -> &mut ChatComponent {
+        &mut self.sessions[self.active].chat
+    }
+
+    pub fn active_id(&self) -> ChatId {
+        self.sessions[self.active].id
+    }
+
+    /// The conversation of `chat_id`, `None` once it is deleted
+    pub fn get(&mut self, chat_id: ChatId) -> Option<&mut ChatComponent> {
+        self.sessions
+            .iter_mut()
+            .find(|session| session.id == chat_id)
+            .map(|session| &mut session.chat)
+    }
+
+    /// The engine of the new conversations, and of those that didn't pick one with `/engine`
+    pub fn set_default_language(&mut self, language: &str) {
+        self.format.language = language.to_string();
+        for session in &mut self.sessions {
+            session.chat.set_default_language(language);
+        }
+    }
+
+    pub fn select(&mut self, chat_id: ChatId) {
+        if let Some(i) = self.sessions.iter().position(|s| s.id == chat_id) {
+            self.active = i;
+        }
+    }
+
+    /// Handle the keys of the sidebar, `false` when `input` is for the chat.
+    pub fn handler_key(&mut self, input: KeyEvent) -> bool {
+        if let Some(editor) = &mut self.rename {
+            match input.code {
+                KeyCode::Enter => {
+                    let name = editor.lines().join(" ").trim().to_string();
+                    if !name.is_empty() {
+                        self.sessions[self.active].name = name;
+                    }
+                    self.rename = None;
+                }
+                KeyCode::Esc => self.rename = None,
+                _ => {
+                    editor.input(input);
+                }
+            }
+            return true;
+        }
+
+        let typing = self.sessions[self.active].chat.is_typing();
+        let delete_armed = std::mem::take(&mut self.delete_armed);
+        match self.keymap.action(&input, typing) {
+            Some(Action::NewChat) => self.create(),
+            Some(Action::RenameChat) => {
+                let mut editor = TextArea::new(vec![self.sessions[self.active].name.clone()]);
+                editor.move_cursor(CursorMove::End);
+                self.rename = Some(editor);
+            }
+            Some(Action::DeleteChat) if delete_armed => self.delete(),
+            Some(Action::DeleteChat) => self.delete_armed = true,
+            Some(Action::PrevChat) => {
+                self.active = self.active.max(1) - 1;
+            }
+            Some(Action::NextChat) => {
+                self.active = (self.active + 1).min(self.sessions.len() - 1);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn render(&mut self, frame: &mut Frame, area: Rect)
+    where
+        Self: Sized,
+    {
+        let editor_height = if self.rename.is_some() { 3 } else { 0 };
+        let vertical = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(editor_height),
+            Constraint::Length(6),
+        ]);
+        let [list_area, editor_area, help_area] = vertical.areas(area);
+
+        let text: Text = self
+            .sessions
+            .iter()
+            .enumerate()
+            .map(|(i, session)| {
+                let style = if i == self.active {
+                    Style::new().reversed()
+                } else {
+                    Style::new()
+                };
+                let mut line = Line::from(Span::styled(session.name.as_str(), style));
+                // waiting for, or in the queue of, the model
+                if session.chat.is_waiting() {
+                    line.push_span(Span::styled(" …", Style::new().fg(Color::Yellow)));
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .into();
+        let list = Paragraph::new(text).block(Block::bordered().title("Chats"));
+        frame.render_widget(list, list_area);
+
+        if let Some(editor) = &mut self.rename {
+            editor.set_block(Block::bordered().title("Rename"));
+            frame.render_widget(editor.widget(), editor_area);
+        }
+
+        let keys = |action| self.keymap.keys(action);
+        let mut help = vec![
+            Line::raw(format!("[{}] new", keys(Action::NewChat))),
+            Line::raw(format!("[{}] rename", keys(Action::RenameChat))),
+            Line::raw(format!("[{}] delete", keys(Action::DeleteChat))),
+            Line::raw(format!(
+                "[{}/{}] switch",
+                keys(Action::PrevChat),
+                keys(Action::NextChat)
+            )),
+        ];
+        if self.delete_armed {
+            help[2] = Line::raw(format!("[{}] again to delete", keys(Action::DeleteChat))).yellow();
+        }
+        frame.render_widget(
+            Paragraph::new(Text::from(help)).block(Block::bordered()),
+            help_area,
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{Block, Paragraph},
+    Frame,
+};
+
+use crate::{chat::settings::Settings, tool_env::approval::ApprovalPolicy};
+
+const ROWS: [&str; 5] = [
+    "Temperature",
+    "Template",
+    "Script engine",
+    "Tool approval",
+    "Context size",
+];
+
+/// Moves to the next (`step` 1) or previous (`step` -1) item of `items` after `current`.
+fn cycle<T: Clone + PartialEq>(items: &[T], current: &T, step: isize) -> T {
+    let i = items.iter().position(|item| item == current).unwrap_or(0) as isize;
+    let n = items.len() as isize;
+    items[(i + step).rem_euclid(n) as usize].clone()
+}
+
+/// The Settings tab, editing a copy of the settings until it is applied.
+pub struct SettingsComponent {
+    settings: Settings,
+    /// what the process started with, for the engine when there was none
+    started: Settings,
+    templates: Vec<String>,
+    engines: Vec<String>,
+    project_path: PathBuf,
+    selected: usize,
+    status: String,
+}
+
+impl SettingsComponent {
+    pub fn new(
+        settings: Settings,
+        templates: Vec<String>,
+        engines: Vec<String>,
+        project_path: PathBuf,
+    ) -> Self {
+        Self {
+            started: settings.clone(),
+            settings,
+            templates,
+            engines,
+            project_path,
+            selected: 0,
+            status: String::new(),
+        }
+    }
+
+    fn value(&self, row: usize) -> String {
+        let s = &self.settings;
+        match row {
+            0 => format!("{:.2}", s.temperature),
+            1 => s.template.clone(),
+            2 => s.engine.clone(),
+            3 => s.approval.as_str().to_string(),
+            _ => s.ctx_size.to_string(),
+        }
+    }
+
+    /// Without an engine no script thread runs, turning one on or off takes a restart.
+    /// The other options are applied live.
+    fn needs_restart(&self, row: usize) -> bool {
+        let (s, started) = (&self.settings, &self.started);
+        row == 2 && s.engine != started.engine && (s.engine == "none" || started.engine == "none")
+    }
+
+    fn change(&mut self, step: isize) {
+        let s = &mut self.settings;
+        match self.selected {
+            0 => s.temperature = (s.temperature + 0.05 * step as f32).clamp(0.0, 2.0),
+            1 if !self.templates.is_empty() => {
+                s.template = cycle(&self.templates, &s.template, step)
+            }
+            2 if !self.engines.is_empty() => s.engine = cycle(&self.engines, &s.engine, step),
+            3 => s.approval = cycle(&ApprovalPolicy::ALL, &s.approval, step),
+            4 => s.ctx_size = s.ctx_size.saturating_add_signed(256 * step as i32).max(256),
+            _ => {}
+        }
+    }
+
+    fn applied(&self) -> String {
+        if (0..ROWS.len()).any(|row| self.needs_restart(row)) {
+            "applied, the options marked (restart) take effect after a restart".to_string()
+        } else {
+            "applied".to_string()
+        }
+    }
+
+    /// The LLM or the script thread could not apply the settings
+    pub fn failed(&mut self, err: String) {
+        self.status = err;
+    }
+
+    /// Apply a temperature set outside the tab, by `/temp`
+    pub fn set_temperature(&mut self, temperature: f32) -> Settings {
+        self.settings.temperature = temperature;
+        self.status = self.applied();
+        self.settings.clone()
+    }
+
+    /// The settings to send to the other threads, when the user applied them.
+    pub fn handler_key(&mut self, input: KeyEvent) -> Option<Settings> {
+        match input.code {
+            KeyCode::Up => self.selected = self.selected.max(1) - 1,
+            KeyCode::Down => self.selected = (self.selected + 1).min(ROWS.len() - 1),
+            KeyCode::Left => self.change(-1),
+            KeyCode::Right => self.change(1),
+            KeyCode::Enter => {
+                self.status = self.applied();
+                return Some(self.settings.clone());
+            }
+            KeyCode::Char('w') => {
+                self.status = match self.settings.save(&self.project_path) {
+                    Ok(()) => format!(
+                        "{}, saved to {}",
+                        self.applied(),
+                        self.project_path.display()
+                    ),
+                    Err(err) => format!("save error: {err:#}"),
+                };
+                return Some(self.settings.clone());
+            }
+            _ => {}
+        }
+        None
+    }
+
+    pub fn render(&mut self, frame: &mut Frame, area: Rect)
+    where
+        Self: Sized,
+    {
+        let vertical = Layout::vertical([Constraint::Min(3), Constraint::Length(4)]);
+        let [rows_area, help_area] = vertical.areas(area);
+
+        let text: Text = ROWS
+            .iter()
+            .enumerate()
+            .map(|(row, label)| {
+                let style = if row == self.selected {
+                    Style::new().reversed()
+                } else {
+                    Style::new()
+                };
+                let mut line = Line::from(vec![
+                    Span::styled(format!("{label:<16}"), Style::new().fg(Color::Cyan)),
+                    Span::styled(format!("< {} >", self.value(row)), style),
+                ]);
+                if self.needs_restart(row) {
+                    line.push_span(Span::styled(" (restart)", Style::new().yellow()));
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .into();
+        let paragraph = Paragraph::new(text).block(Block::bordered().title("Settings"));
+        frame.render_widget(paragraph, rows_area);
+
+        let help = Paragraph::new(Text::from(vec![
+            Line::raw("[Up/Down] select  [Left/Right] change  [Enter] apply  [w] apply and save"),
+            Line::raw(self.status.as_str()).yellow(),
+        ]))
+        .block(Block::bordered());
+        frame.render_widget(help, help_area);
+    }
+}
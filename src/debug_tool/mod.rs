@@ -1,6 +1,7 @@
 use crate::{
-    chat::im_channel::{Message, Role},
+    chat::im_channel::{Control, Message, Role},
     llm::local_llm::Token,
+    tool_env::approval::Approval,
 };
 
 pub fn echo_assistant(
@@ -11,14 +12,17 @@ pub fn echo_assistant(
         while let Ok(input) = rx.recv() {
             match input {
                 Message {
+                    chat_id,
                     role: Role::User,
                     contont: Token::End(message),
                 } => {
                     let _ = tx.send(Message {
+                        chat_id,
                         role: Role::Assistant,
                         contont: Token::Start,
                     });
                     let _ = tx.send(Message {
+                        chat_id,
                         role: Role::Assistant,
                         contont: Token::End(message),
                     });
@@ -53,6 +57,7 @@ impl TerminalApp {
                 break;
             }
             let _ = tx.send(Message {
+                chat_id: 0,
                 role: Role::User,
                 contont: Token::End(line),
             });
@@ -67,6 +72,9 @@ impl TerminalApp {
         // create app and run it
         std::thread::spawn(move || Self::listen_user_input(input_tx));
 
+        // the conversation whose call waits for `y` or anything else
+        let mut confirming = None;
+
         loop {
             let input = crossbeam::select! {
                 recv(input_rx) -> input =>{
@@ -87,8 +95,36 @@ impl TerminalApp {
 
             println!("{input:?}");
 
-            if let Role::User = input.role {
-                let _ = self.tx.send(input);
+            match input {
+                Message {
+                    chat_id,
+                    contont: Token::Control(Control::ConfirmCall { .. }),
+                    ..
+                } => {
+                    confirming = Some(chat_id);
+                    log::info!("answer `y` to approve the call, anything else denies it");
+                }
+                Message {
+                    role: Role::User,
+                    contont: Token::End(line),
+                    ..
+                } if confirming.is_some() => {
+                    let chat_id = confirming.take().unwrap_or_default();
+                    let approval = if line.trim() == "y" {
+                        Approval::Approve
+                    } else {
+                        Approval::Deny
+                    };
+                    let _ = self.tx.send(Message {
+                        chat_id,
+                        role: Role::User,
+                        contont: Token::Control(Control::ConfirmReply(approval)),
+                    });
+                }
+                input if input.role == Role::User => {
+                    let _ = self.tx.send(input);
+                }
+                _ => {}
             }
         }
 
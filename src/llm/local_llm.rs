@@ -1,15 +1,24 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use simple_llama::{
     llm::{ChatRequest, LlamaCtx, SimpleOption},
     Content,
 };
 
-use crate::chat::im_channel::{Message, MessageRx, MessageTx, Role};
+use crate::chat::{
+    im_channel::{ChatId, Control, Message, MessageRx, MessageTx, Role},
+    settings::Settings,
+};
 
 struct ScriptHook {
     rx: MessageRx,
     tx: MessageTx,
+    /// changed by the Settings tab, read before each reply
+    temperature: f32,
 }
 
 #[derive(Debug, Clone)]
@@ -17,18 +26,85 @@ pub enum Token {
     Start,
     Chunk(String),
     End(String),
+    /// a tool result, `result` is what the model gets
+    ToolEnd {
+        result: String,
+        /// all of it when `result` was shortened, for the user
+        full: Option<String>,
+        /// how long the script ran
+        duration: Duration,
+    },
+    Control(Control),
+}
+
+enum Request {
+    Chat(ChatId, Content),
+    Delete(ChatId),
+    /// keep the history before the user's `n`th message
+    Rewind(ChatId, usize),
+    System(ChatId, String),
+    Load(ChatId, Vec<(Role, String)>),
+    Settings(ChatId, Settings),
 }
 
 impl ScriptHook {
-    fn get_input(&mut self) -> anyhow::Result<Option<Content>> {
+    /// The next message to answer, in the order they were sent, whatever the conversation
+    fn get_input(&mut self) -> anyhow::Result<Option<Request>> {
         while let Ok(input) = self.rx.recv() {
             match input {
                 Message {
+                    chat_id,
                     role,
                     contont: Token::End(message),
-                } if role == Role::User || role == Role::Tool => {
+                } if role == Role::User => {
                     let c = simple_llama::llm::Content { role, message };
-                    return Ok(Some(c));
+                    return Ok(Some(Request::Chat(chat_id, c)));
+                }
+                Message {
+                    chat_id,
+                    role: Role::Tool,
+                    contont: Token::ToolEnd { result, .. },
+                } => {
+                    let c = simple_llama::llm::Content {
+                        role: Role::Tool,
+                        message: result,
+                    };
+                    return Ok(Some(Request::Chat(chat_id, c)));
+                }
+                Message {
+                    chat_id,
+                    role: Role::User,
+                    contont: Token::Control(Control::Settings(settings)),
+                } => {
+                    return Ok(Some(Request::Settings(chat_id, settings)));
+                }
+                Message {
+                    chat_id,
+                    contont: Token::Control(Control::DeleteChat),
+                    ..
+                } => {
+                    return Ok(Some(Request::Delete(chat_id)));
+                }
+                Message {
+                    chat_id,
+                    contont: Token::Control(Control::Rewind(n)),
+                    ..
+                } => {
+                    return Ok(Some(Request::Rewind(chat_id, n)));
+                }
+                Message {
+                    chat_id,
+                    contont: Token::Control(Control::SystemPrompt(prompt)),
+                    ..
+                } => {
+                    return Ok(Some(Request::System(chat_id, prompt)));
+                }
+                Message {
+                    chat_id,
+                    contont: Token::Control(Control::LoadHistory(history)),
+                    ..
+                } => {
+                    return Ok(Some(Request::Load(chat_id, history)));
                 }
 
                 _ => {}
@@ -37,8 +113,9 @@ impl ScriptHook {
         Ok(None)
     }
 
-    fn token_callback(&mut self, token: Token) -> anyhow::Result<()> {
+    fn token_callback(&mut self, chat_id: ChatId, token: Token) -> anyhow::Result<()> {
         self.tx.send(Message {
+            chat_id,
             role: Role::Assistant,
             contont: token,
         })?;
@@ -46,39 +123,187 @@ impl ScriptHook {
     }
 }
 
+struct Chat {
+    prompts: Vec<Arc<Content>>,
+    /// how many of the prompts come before the conversation: system prompts and examples
+    preset: usize,
+}
+
+impl Chat {
+    fn new(preset: &[Arc<Content>]) -> Self {
+        Chat {
+            prompts: preset.to_vec(),
+            preset: preset.len(),
+        }
+    }
+
+    /// Replace the leading system prompts of the preset with `prompt`. A loaded
+    /// conversation may have system messages of its own, they are kept.
+    fn set_system(&mut self, prompt: String) {
+        let n = self.prompts[..self.preset]
+            .iter()
+            .take_while(|c| c.role == Role::System)
+            .count();
+        let system = Arc::new(Content {
+            role: Role::System,
+            message: prompt,
+        });
+        self.prompts.splice(0..n, [system]);
+        self.preset = self.preset - n + 1;
+    }
+}
+
+/// Loads the model with a template and a context size
+pub type LoadModel = Box<dyn FnMut(&str, u32) -> anyhow::Result<LlamaCtx>>;
+
+/// One model shared by all the conversations, answering them one at a time.
 pub struct LocalLlama {
     ctx: LlamaCtx,
+    /// loads the model again when the template or the context size change
+    load_model: LoadModel,
+    template: String,
+    ctx_size: u32,
     hook: ScriptHook,
+    /// the system prompts every conversation starts with
     prompts: Vec<Arc<Content>>,
+    chats: HashMap<ChatId, Chat>,
+    /// a tool result may still come for them, it must not bring them back
+    deleted: HashSet<ChatId>,
 }
 
 impl LocalLlama {
-    pub fn new(ctx: LlamaCtx, prompts: Vec<Arc<Content>>, rx: MessageRx, tx: MessageTx) -> Self {
-        let hook = ScriptHook { rx, tx };
-        LocalLlama { ctx, hook, prompts }
+    pub fn new(
+        mut load_model: LoadModel,
+        prompts: Vec<Arc<Content>>,
+        settings: &Settings,
+        rx: MessageRx,
+        tx: MessageTx,
+    ) -> anyhow::Result<Self> {
+        let ctx = load_model(&settings.template, settings.ctx_size)?;
+        let hook = ScriptHook {
+            rx,
+            tx,
+            temperature: settings.temperature,
+        };
+        Ok(LocalLlama {
+            ctx,
+            load_model,
+            template: settings.template.clone(),
+            ctx_size: settings.ctx_size,
+            hook,
+            prompts,
+            chats: HashMap::new(),
+            deleted: HashSet::new(),
+        })
+    }
+
+    fn chat(&mut self, chat_id: ChatId) -> &mut Chat {
+        self.chats
+            .entry(chat_id)
+            .or_insert_with(|| Chat::new(&self.prompts))
+    }
+
+    fn rewind(&mut self, chat_id: ChatId, n: usize) {
+        let Some(chat) = self.chats.get_mut(&chat_id) else {
+            return;
+        };
+        // the preset may have user messages too, they are not the user's
+        let at = chat.prompts[chat.preset..]
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.role == Role::User)
+            .nth(n)
+            .map(|(i, _)| chat.preset + i);
+        if let Some(at) = at {
+            chat.prompts.truncate(at);
+        }
+    }
+
+    fn set_system(&mut self, chat_id: ChatId, prompt: String) {
+        self.chat(chat_id).set_system(prompt);
+    }
+
+    fn load(&mut self, chat_id: ChatId, history: Vec<(Role, String)>) {
+        let chat = self.chat(chat_id);
+        chat.prompts.truncate(chat.preset);
+        chat.prompts.extend(
+            history
+                .into_iter()
+                .map(|(role, message)| Arc::new(Content { role, message })),
+        );
+    }
+
+    /// The conversations are kept, the new template formats them from the next reply on.
+    /// The model in use stays when the new one doesn't load.
+    fn apply_settings(&mut self, settings: Settings) -> anyhow::Result<()> {
+        self.hook.temperature = settings.temperature;
+        if settings.template == self.template && settings.ctx_size == self.ctx_size {
+            return Ok(());
+        }
+        self.ctx = (self.load_model)(&settings.template, settings.ctx_size)?;
+        self.template = settings.template;
+        self.ctx_size = settings.ctx_size;
+        Ok(())
     }
 
     pub fn run_loop(&mut self) -> anyhow::Result<()> {
         loop {
-            let c = match self.hook.get_input()? {
-                Some(c) => c,
+            let (chat_id, c) = match self.hook.get_input()? {
+                Some(Request::Chat(chat_id, c)) => (chat_id, c),
+                Some(Request::Delete(chat_id)) => {
+                    self.chats.remove(&chat_id);
+                    self.deleted.insert(chat_id);
+                    continue;
+                }
+                Some(
+                    Request::Chat(chat_id, _)
+                    | Request::System(chat_id, _)
+                    | Request::Load(chat_id, _),
+                ) if self.deleted.contains(&chat_id) => continue,
+                Some(Request::Rewind(chat_id, n)) => {
+                    self.rewind(chat_id, n);
+                    continue;
+                }
+                Some(Request::System(chat_id, prompt)) => {
+                    self.set_system(chat_id, prompt);
+                    continue;
+                }
+                Some(Request::Load(chat_id, history)) => {
+                    self.load(chat_id, history);
+                    continue;
+                }
+                Some(Request::Settings(chat_id, settings)) => {
+                    if let Err(err) = self.apply_settings(settings) {
+                        let err = format!("reload the model error: {err:#}");
+                        let err = Token::Control(Control::SettingsError(err));
+                        self.hook.token_callback(chat_id, err)?;
+                    }
+                    continue;
+                }
                 None => return Err(anyhow::anyhow!("input is clone")),
             };
-            self.prompts.push(Arc::new(c));
+            // not `self.chat()`, the model and the hook are used while it is borrowed
+            let prompts = &mut self
+                .chats
+                .entry(chat_id)
+                .or_insert_with(|| Chat::new(&self.prompts))
+                .prompts;
+            prompts.push(Arc::new(c));
 
-            self.hook.token_callback(Token::Start)?;
+            self.hook.token_callback(chat_id, Token::Start)?;
             let mut stream = self.ctx.chat(ChatRequest {
-                prompts: self.prompts.clone(),
-                simple_option: SimpleOption::Temp(0.7),
+                prompts: prompts.clone(),
+                simple_option: SimpleOption::Temp(self.hook.temperature),
             })?;
 
             for token in &mut stream {
-                self.hook.token_callback(Token::Chunk(token))?;
+                self.hook.token_callback(chat_id, Token::Chunk(token))?;
             }
 
             let message: String = stream.into();
-            self.hook.token_callback(Token::End(message.clone()))?;
-            self.prompts.push(Arc::new(Content {
+            self.hook
+                .token_callback(chat_id, Token::End(message.clone()))?;
+            prompts.push(Arc::new(Content {
                 role: Role::Assistant,
                 message,
             }));
@@ -93,3 +318,44 @@ impl LocalLlama {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content(role: Role, message: &str) -> Arc<Content> {
+        Arc::new(Content {
+            role,
+            message: message.to_string(),
+        })
+    }
+
+    fn messages(chat: &Chat) -> Vec<&str> {
+        chat.prompts.iter().map(|c| c.message.as_str()).collect()
+    }
+
+    #[test]
+    fn system_prompt_replaces_the_preset_ones() {
+        let preset = [
+            content(Role::System, "a"),
+            content(Role::System, "b"),
+            content(Role::User, "example"),
+        ];
+        let mut chat = Chat::new(&preset);
+        chat.prompts.push(content(Role::User, "hi"));
+        chat.set_system("new".to_string());
+        assert_eq!(messages(&chat), ["new", "example", "hi"]);
+        assert_eq!(chat.preset, 2);
+    }
+
+    #[test]
+    fn system_messages_after_the_preset_are_kept() {
+        let mut chat = Chat::new(&[]);
+        // a loaded conversation starting with its own system messages
+        chat.prompts.push(content(Role::System, "loaded 1"));
+        chat.prompts.push(content(Role::System, "loaded 2"));
+        chat.set_system("new".to_string());
+        assert_eq!(messages(&chat), ["new", "loaded 1", "loaded 2"]);
+        assert_eq!(chat.preset, 1);
+    }
+}
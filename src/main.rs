@@ -1,10 +1,23 @@
-use std::{collections::HashMap, error::Error, num::NonZeroU32, sync::Arc};
-
-use chat::im_channel;
-use clap::Parser;
+use std::{
+    collections::HashMap,
+    error::Error,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chat::{im_channel, settings::Settings};
+use clap::{Parser, ValueEnum};
 use llm::local_llm;
 use simple_llama::llm::{self as llama, PromptTemplate};
-use tool_env::ScriptExecutor;
+use tool_env::{
+    approval::{ApprovalPolicy, Approver},
+    audit::{AuditFilter, AuditLog},
+    mcp_server::McpServer,
+    output::{OutputLimit, OutputLimiter},
+    protocol::Protocol,
+    ScriptEngin, ScriptExecutor,
+};
 
 mod chat;
 mod component;
@@ -13,9 +26,13 @@ mod llm;
 mod tool_env;
 
 #[derive(Debug, clap::Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(long, short, required = true)]
-    project_path: String,
+    project_path: Option<String>,
 
     /// full prompt chat
     #[arg(long)]
@@ -24,8 +41,35 @@ struct Args {
     #[arg(long)]
     debug_llm: bool,
 
-    #[arg(short, long, value_enum, default_value = "none")]
-    engine: Engine,
+    /// overrides `engine` of project.toml
+    #[arg(short, long, value_enum)]
+    engine: Option<Engine>,
+
+    /// serve the tools, and `eval_script` when an engine is set, as an MCP server on stdio
+    #[arg(long)]
+    mcp_server: bool,
+
+    /// with `--mcp-server`, run the calls `approval = "ask"` would ask about instead of refusing them
+    #[arg(long, requires = "mcp_server")]
+    allow_side_effects: bool,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// print the audit log records matching the filters, as JSON lines
+    Audit {
+        /// the `path` of `[audit]` in project.toml
+        log: PathBuf,
+        /// records where this tool was called
+        #[arg(long)]
+        tool: Option<String>,
+        /// RFC 3339 time or YYYY-MM-DD
+        #[arg(long)]
+        since: Option<String>,
+        /// RFC 3339 time or YYYY-MM-DD, exclusive
+        #[arg(long)]
+        until: Option<String>,
+    },
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -33,20 +77,76 @@ struct Project {
     model_path: String,
     prompts: String,
     template: String,
+    engine: Option<Engine>,
+    #[serde(default)]
+    protocol: Protocol,
+    #[serde(default)]
+    approval: ApprovalPolicy,
+    #[serde(default)]
+    tool_output: OutputLimit,
     run: RunOptions,
     templates: HashMap<String, PromptTemplate>,
+    #[serde(default)]
+    plugins: Vec<tool_env::wasm::PluginConfig>,
+    #[serde(default)]
+    mcp_servers: Vec<tool_env::mcp::McpServerConfig>,
+    #[serde(default)]
+    prelude: Prelude,
+    #[serde(default)]
+    tools: Vec<tool_env::config_tool::ToolConfig>,
+    filesystem: Option<tool_env::fs::FsConfig>,
+    sqlite: Option<tool_env::sqlite::SqliteConfig>,
+    audit: Option<tool_env::audit::AuditConfig>,
+    /// a TOML file binding the actions of the chat to other keys, relative to the project file
+    keymap: Option<String>,
+}
+
+/// Script files evaluated into the engine before the first message
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+struct Prelude {
+    #[serde(default)]
+    lua: Vec<String>,
+    #[serde(default)]
+    rhai: Vec<String>,
+    #[serde(default)]
+    js: Vec<String>,
+}
+
+impl Prelude {
+    fn load(&self, engine: &Engine) -> anyhow::Result<Vec<(String, String)>> {
+        let paths = match engine {
+            Engine::Lua => &self.lua,
+            Engine::Rhai => &self.rhai,
+            Engine::Js => &self.js,
+            Engine::None => return Ok(Vec::new()),
+        };
+        paths
+            .iter()
+            .map(|path| {
+                let code = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("prelude `{path}`: {e}"))?;
+                Ok((path.clone(), code))
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
 struct RunOptions {
     #[serde(default)]
     ctx_size: u32,
+    #[serde(default = "default_temperature")]
+    temperature: f32,
     #[serde(default)]
     n_batch: u32,
     #[serde(default)]
     n_gpu_layers: u32,
 }
 
+fn default_temperature() -> f32 {
+    0.7
+}
+
 impl RunOptions {
     fn fill_default_value(&mut self) {
         if self.ctx_size == 0 {
@@ -61,41 +161,149 @@ impl RunOptions {
     }
 }
 
-#[derive(Debug, Clone, clap::ValueEnum)]
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum Engine {
     None,
     Lua,
     Rhai,
+    Js,
+}
+
+/// The name of `engine` on the command line and in project.toml
+fn engine_name(engine: &Engine) -> String {
+    engine
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+fn new_engine(
+    engine: &Engine,
+    tools: tool_env::Tools,
+    preludes: &[(String, String)],
+) -> anyhow::Result<Option<Box<dyn ScriptEngin>>> {
+    let mut engine: Box<dyn ScriptEngin> = match engine {
+        Engine::Lua => {
+            Box::new(tool_env::lua::LuaEngine::new(tools).map_err(|e| anyhow::anyhow!("{e}"))?)
+        }
+        Engine::Rhai => Box::new(tool_env::rhai::RhaiEngine::new(tools)),
+        Engine::Js => {
+            Box::new(tool_env::js::JsEngine::new(tools).map_err(|e| anyhow::anyhow!("{e}"))?)
+        }
+        Engine::None => return Ok(None),
+    };
+    tool_env::load_preludes(engine.as_mut(), preludes)?;
+    Ok(Some(engine))
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
     let cli = Args::parse();
+    if let Some(Command::Audit {
+        log,
+        tool,
+        since,
+        until,
+    }) = cli.command
+    {
+        let filter = AuditFilter { tool, since, until };
+        tool_env::audit::query(&log, &filter, &mut std::io::stdout().lock())?;
+        return Ok(());
+    }
+    let project_path = cli.project_path.unwrap_or_default();
     let mut project: Project =
-        toml::from_str(&std::fs::read_to_string(&cli.project_path).unwrap()).unwrap();
+        toml::from_str(&std::fs::read_to_string(&project_path).unwrap()).unwrap();
     project.run.fill_default_value();
+    let engine = cli
+        .engine
+        .clone()
+        .or(project.engine.clone())
+        .unwrap_or(Engine::None);
 
     let (chan_close_tx, chan_close_rx) = crossbeam::channel::bounded(1);
 
     let mut chan = im_channel::ImChannel::new(chan_close_rx);
 
     let (tx, rx) = chan.register(tool_env::filter);
-    match cli.engine {
-        Engine::Lua => {
-            std::thread::spawn(move || {
-                let script_executor =
-                    ScriptExecutor::new(tool_env::lua::new_lua().unwrap(), rx, tx);
-                script_executor.run_loop()
-            });
-        }
-        Engine::Rhai => {
-            std::thread::spawn(move || {
-                let script_executor = ScriptExecutor::new(tool_env::rhai::new_rhai(), rx, tx);
-                script_executor.run_loop()
-            });
+    let protocol = project.protocol;
+    let approval = match project.approval {
+        // nobody to ask here
+        ApprovalPolicy::Ask if cli.mcp_server && cli.allow_side_effects => {
+            log::warn!("--allow-side-effects: tool calls with side effects run without asking");
+            ApprovalPolicy::Allow
         }
-        Engine::None => {}
+        ApprovalPolicy::Ask if cli.mcp_server => ApprovalPolicy::Deny,
+        policy => policy,
     };
+    let approver = Arc::new(Approver::new(approval, rx.clone(), tx.clone()));
+    let mut tools = tool_env::builtin::builtin_tools();
+    tools.extend(tool_env::wasm::load_plugins(&project.plugins)?);
+    tools.extend(tool_env::mcp::connect_all(&project.mcp_servers)?);
+    if let Some(filesystem) = &project.filesystem {
+        tools.extend(tool_env::fs::fs_tools(filesystem)?);
+    }
+    if let Some(sqlite) = &project.sqlite {
+        tools.extend(tool_env::sqlite::sqlite_tools(sqlite)?);
+    }
+    // a declared tool replaces the builtin of the same name
+    let declared = tool_env::config_tool::load_tools(&project.tools)?;
+    tools.retain(|tool| declared.iter().all(|d| d.name() != tool.name()));
+    tools.extend(declared);
+    let limiter = OutputLimiter::new(project.tool_output);
+    if !cli.mcp_server {
+        tools.extend(limiter.tools());
+    }
+    let mut tools = tool_env::approval::guard(tools, &approver);
+    let audit = project.audit.as_ref().map(AuditLog::open).transpose()?;
+    if let Some(audit) = &audit {
+        tools = audit.record_calls(tools);
+    }
+
+    if cli.mcp_server {
+        let preludes = project.prelude.load(&engine)?;
+        let engine = new_engine(&engine, tools.clone(), &preludes)?;
+        McpServer::new(engine, tools, audit).run_loop()?;
+        return Ok(());
+    }
+
+    let settings = Settings {
+        temperature: project.run.temperature,
+        template: project.template.clone(),
+        engine: engine_name(&engine),
+        approval,
+        ctx_size: project.run.ctx_size,
+    };
+    if engine != Engine::None {
+        // the engines are not Send, build them on their own thread and wait for the preludes
+        let (ready_tx, ready_rx) = crossbeam::channel::bounded(1);
+        let prelude = project.prelude.clone();
+        let declared = tools.clone();
+        std::thread::spawn(move || {
+            // one engine per conversation, `/engine` may pick another language for it
+            let language = engine_name(&engine);
+            let factory: Box<dyn FnMut(&str) -> anyhow::Result<Box<dyn ScriptEngin>>> =
+                Box::new(move |language| {
+                    let engine = Engine::from_str(language, true)
+                        .map_err(|_| anyhow::anyhow!("unknown engine `{language}`"))?;
+                    let preludes = prelude.load(&engine)?;
+                    new_engine(&engine, tools.clone(), &preludes)?
+                        .ok_or_else(|| anyhow::anyhow!("no script engine"))
+                });
+            match ScriptExecutor::new(
+                &language, factory, declared, protocol, approver, limiter, audit, rx, tx,
+            ) {
+                Ok(executor) => {
+                    let _ = ready_tx.send(Ok(()));
+                    executor.run_loop()
+                }
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                }
+            }
+        });
+        ready_rx.recv()??;
+    }
 
     let llama_result;
 
@@ -111,35 +319,45 @@ fn main() -> Result<(), Box<dyn Error>> {
         let prompts = prompt.remove("content").unwrap();
         let prompts = prompts.into_iter().map(Arc::new).collect();
 
-        let template = project
-            .templates
-            .get(&project.template)
-            .ok_or(anyhow::anyhow!("template not found"))?
-            .clone();
-
         let (wait_tx, wait_rx) = crossbeam::channel::bounded(1);
+        let settings = settings.clone();
+        let templates = project.templates.clone();
 
         llama_result = std::thread::spawn(move || {
-            let model_params: simple_llama::llm::LlamaModelParams =
-                simple_llama::llm::LlamaModelParams::default()
-                    .with_n_gpu_layers(project.run.n_gpu_layers);
+            // the Settings tab may change the template and the context size
+            let load_model: local_llm::LoadModel = Box::new(move |template, ctx_size| {
+                let template = templates
+                    .get(template)
+                    .ok_or(anyhow::anyhow!("template `{template}` not found"))?
+                    .clone();
 
-            let llm = llama::LlmModel::new(project.model_path, model_params, template)
-                .map_err(|e| anyhow::anyhow!(e))?;
+                let model_params: simple_llama::llm::LlamaModelParams =
+                    simple_llama::llm::LlamaModelParams::default()
+                        .with_n_gpu_layers(project.run.n_gpu_layers);
 
-            let ctx_params = llama::LlamaContextParams::default()
-                .with_n_ctx(NonZeroU32::new(project.run.ctx_size))
-                .with_n_batch(project.run.n_batch);
+                let llm = llama::LlmModel::new(project.model_path.clone(), model_params, template)
+                    .map_err(|e| anyhow::anyhow!(e))?;
 
-            let ctx = llama::LlamaCtx::new(llm, ctx_params).unwrap();
+                let ctx_params = llama::LlamaContextParams::default()
+                    .with_n_ctx(NonZeroU32::new(ctx_size))
+                    .with_n_batch(project.run.n_batch);
 
-            let mut local_llama = llm::local_llm::LocalLlama::new(ctx, prompts, rx, tx);
-            wait_tx.send(()).unwrap();
+                llama::LlamaCtx::new(llm, ctx_params).map_err(|e| anyhow::anyhow!("{e:?}"))
+            });
 
-            local_llama.run_loop()
+            match llm::local_llm::LocalLlama::new(load_model, prompts, &settings, rx, tx) {
+                Ok(mut local_llama) => {
+                    let _ = wait_tx.send(Ok(()));
+                    local_llama.run_loop()
+                }
+                Err(err) => {
+                    let _ = wait_tx.send(Err(err));
+                    Ok(())
+                }
+            }
         });
 
-        wait_rx.recv()?;
+        wait_rx.recv()??;
     }
 
     let res;
@@ -152,7 +370,29 @@ fn main() -> Result<(), Box<dyn Error>> {
         res = app.run_loop();
     } else {
         let (tx, rx) = chan.register(component::App::filter);
-        let app = component::App::new(rx, tx);
+        let mut templates: Vec<String> = project.templates.keys().cloned().collect();
+        templates.sort();
+        let engines = Engine::value_variants().iter().map(engine_name).collect();
+        let format = component::markdown::MessageFormat {
+            protocol,
+            language: settings.engine.clone(),
+        };
+        let history = component::history::InputHistory::load(Path::new(&project_path));
+        // relative to the project file, like the history
+        let keymap = match &project.keymap {
+            Some(path) => {
+                let dir = Path::new(&project_path).parent().unwrap_or(Path::new(""));
+                component::keymap::Keymap::load(&dir.join(path))?
+            }
+            None => Default::default(),
+        };
+        let settings = component::settings::SettingsComponent::new(
+            settings,
+            templates,
+            engines,
+            project_path.into(),
+        );
+        let app = component::App::new(rx, tx, format, history, keymap, settings);
 
         std::thread::spawn(move || chan.run_loop());
 
@@ -0,0 +1,244 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::Value;
+
+use crate::{
+    chat::im_channel::{ChatId, Control, Message, MessageRx, MessageTx, Role},
+    llm::local_llm::Token,
+};
+
+use super::{Tool, ToolCall, Tools};
+
+/// What to do when a script calls a tool that is not pure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApprovalPolicy {
+    /// Ask the user every time.
+    #[default]
+    Ask,
+    /// Run the call without asking.
+    Allow,
+    /// Refuse the call without asking.
+    Deny,
+}
+
+impl ApprovalPolicy {
+    pub const ALL: [ApprovalPolicy; 3] = [Self::Ask, Self::Allow, Self::Deny];
+
+    /// The name used in project.toml
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ask => "ask",
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+        }
+    }
+}
+
+/// The user's answer to [`Control::ConfirmCall`].
+#[derive(Debug, Clone)]
+pub enum Approval {
+    Approve,
+    Deny,
+    /// Run the call with these arguments instead.
+    Edit(Vec<Value>),
+}
+
+/// A call the policy or the user refused, told apart from failed calls in the audit log.
+#[derive(Debug)]
+pub struct Denied(pub String);
+
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Denied {}
+
+/// Asks the UI before a side-effecting tool runs. The script executor is
+/// blocked in `eval` meanwhile, so the answer is read from its own channel.
+pub struct Approver {
+    policy: Mutex<ApprovalPolicy>,
+    code: Mutex<(ChatId, String)>,
+    /// messages for the executor that came in while waiting for an answer
+    deferred: Mutex<VecDeque<Message>>,
+    rx: MessageRx,
+    tx: MessageTx,
+}
+
+impl Approver {
+    pub fn new(policy: ApprovalPolicy, rx: MessageRx, tx: MessageTx) -> Self {
+        Approver {
+            policy: Mutex::new(policy),
+            code: Mutex::new((0, String::new())),
+            deferred: Mutex::new(VecDeque::new()),
+            rx,
+            tx,
+        }
+    }
+
+    pub fn set_policy(&self, policy: ApprovalPolicy) {
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    /// The script being evaluated and its conversation, shown to the user with the call.
+    pub fn set_code(&self, chat_id: ChatId, code: &str) {
+        *self.code.lock().unwrap() = (chat_id, code.to_string());
+    }
+
+    /// The next message the executor missed while a call waited for the user
+    pub fn take_deferred(&self) -> Option<Message> {
+        self.deferred.lock().unwrap().pop_front()
+    }
+
+    fn approve(&self, name: &str, args: Vec<Value>) -> anyhow::Result<Vec<Value>> {
+        let policy = *self.policy.lock().unwrap();
+        match policy {
+            ApprovalPolicy::Allow => return Ok(args),
+            ApprovalPolicy::Deny => {
+                return Err(Denied(format!("calling `{name}` is not allowed")).into())
+            }
+            ApprovalPolicy::Ask => {}
+        }
+
+        let (chat_id, code) = self.code.lock().unwrap().clone();
+        self.tx.send(Message {
+            chat_id,
+            role: Role::Tool,
+            contont: Token::Control(Control::ConfirmCall {
+                code,
+                call: ToolCall {
+                    name: name.to_string(),
+                    args: args.clone(),
+                },
+            }),
+        })?;
+
+        while let Ok(message) = self.rx.recv() {
+            match message.contont {
+                Token::Control(Control::ConfirmReply(approval)) if message.chat_id == chat_id => {
+                    return match approval {
+                        Approval::Approve => Ok(args),
+                        Approval::Edit(args) => Ok(args),
+                        Approval::Deny => {
+                            Err(Denied(format!("the user denied calling `{name}`")).into())
+                        }
+                    }
+                }
+                // only one call waits at a time, a reply from elsewhere is stale
+                Token::Control(Control::ConfirmReply(_)) => {
+                    log::warn!("dropped an answer from conversation {}", message.chat_id);
+                }
+                Token::Control(Control::DeleteChat) if message.chat_id == chat_id => {
+                    // the executor forgets the conversation once the script is over
+                    self.deferred.lock().unwrap().push_back(message);
+                    anyhow::bail!("the conversation was deleted");
+                }
+                contont => self
+                    .deferred
+                    .lock()
+                    .unwrap()
+                    .push_back(Message { contont, ..message }),
+            }
+        }
+        anyhow::bail!("channel closed while waiting for approval")
+    }
+}
+
+struct Guarded {
+    tool: Arc<dyn Tool>,
+    approver: Arc<Approver>,
+}
+
+impl Tool for Guarded {
+    fn name(&self) -> &str {
+        self.tool.name()
+    }
+
+    fn description(&self) -> &str {
+        self.tool.description()
+    }
+
+    fn params(&self) -> Vec<String> {
+        self.tool.params()
+    }
+
+    fn is_pure(&self) -> bool {
+        false
+    }
+
+    fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+        let args = self.approver.approve(self.tool.name(), args)?;
+        self.tool.call(args)
+    }
+}
+
+/// Route every tool that is not pure through `approver`.
+pub fn guard(tools: Tools, approver: &Arc<Approver>) -> Tools {
+    tools
+        .into_iter()
+        .map(|tool| {
+            if tool.is_pure() {
+                tool
+            } else {
+                Arc::new(Guarded {
+                    tool,
+                    approver: approver.clone(),
+                }) as Arc<dyn Tool>
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control(chat_id: ChatId, control: Control) -> Message {
+        Message {
+            chat_id,
+            role: Role::User,
+            contont: Token::Control(control),
+        }
+    }
+
+    #[test]
+    fn only_the_asked_conversation_answers() {
+        let (to_approver, rx) = crossbeam::channel::unbounded();
+        let (tx, from_approver) = crossbeam::channel::unbounded();
+        let approver = Arc::new(Approver::new(ApprovalPolicy::Ask, rx, tx));
+        approver.set_code(1, "send_sms(1)");
+
+        let asking = approver.clone();
+        let call = std::thread::spawn(move || asking.approve("send_sms", vec![1.into()]));
+        let asked = from_approver.recv().unwrap();
+        assert_eq!(asked.chat_id, 1);
+        assert!(matches!(
+            asked.contont,
+            Token::Control(Control::ConfirmCall { .. })
+        ));
+
+        to_approver
+            .send(control(2, Control::ConfirmReply(Approval::Approve)))
+            .unwrap();
+        to_approver.send(control(2, Control::ResetScript)).unwrap();
+        to_approver
+            .send(control(1, Control::ConfirmReply(Approval::Deny)))
+            .unwrap();
+
+        let err = call.join().unwrap().unwrap_err();
+        assert!(err.downcast_ref::<Denied>().is_some());
+        // the other messages are kept for the executor, the stray answer is not
+        let deferred = approver.take_deferred().unwrap();
+        assert_eq!(deferred.chat_id, 2);
+        assert!(matches!(
+            deferred.contont,
+            Token::Control(Control::ResetScript)
+        ));
+        assert!(approver.take_deferred().is_none());
+    }
+}
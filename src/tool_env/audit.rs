@@ -0,0 +1,356 @@
+//! Append-only record of every script the executor ran.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone};
+use serde_json::Value;
+
+use super::{approval::Denied, error::ScriptError, Tool, ToolCall, Tools};
+use crate::chat::im_channel::ChatId;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct AuditConfig {
+    /// JSON lines, one per evaluated script
+    pub path: PathBuf,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Record<'a> {
+    time: String,
+    engine: &'a str,
+    conversation: String,
+    /// the scripts the conversation ran so far, this one included. Not the user's
+    /// turns: a reply to one message may run several scripts.
+    seq: u64,
+    duration_ms: u64,
+    code: &'a str,
+    calls: Vec<CallRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a ScriptError>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct CallRecord {
+    #[serde(flatten)]
+    call: ToolCall,
+    /// `ok`, `error`, or `denied` by the approval policy or the user
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+type Calls = Arc<Mutex<Vec<CallRecord>>>;
+
+pub struct AuditLog {
+    file: File,
+    /// tells the runs of the app apart, conversation ids start over in each
+    session: String,
+    /// scripts run by each conversation
+    seqs: HashMap<ChatId, u64>,
+    calls: Calls,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .with_context(|| format!("audit log `{}`", config.path.display()))?;
+        let session = format!(
+            "{}-{}",
+            Local::now().format("%Y%m%dT%H%M%S"),
+            std::process::id()
+        );
+        Ok(AuditLog {
+            file,
+            session,
+            seqs: HashMap::new(),
+            calls: Default::default(),
+        })
+    }
+
+    /// Wrap `tools` so their calls end up in the next record. Wrap the guarded tools,
+    /// so the denied calls are recorded too.
+    pub fn record_calls(&self, tools: Tools) -> Tools {
+        tools
+            .into_iter()
+            .map(|tool| {
+                Arc::new(Recorded {
+                    tool,
+                    calls: self.calls.clone(),
+                }) as Arc<dyn Tool>
+            })
+            .collect()
+    }
+
+    pub fn write(
+        &mut self,
+        engine: &str,
+        chat_id: ChatId,
+        code: &str,
+        duration: Duration,
+        result: &Result<Value, ScriptError>,
+    ) -> anyhow::Result<()> {
+        let seq = self.seqs.entry(chat_id).or_default();
+        *seq += 1;
+        let calls = std::mem::take(&mut *self.calls.lock().unwrap());
+        let record = Record {
+            time: Local::now().to_rfc3339(),
+            engine,
+            conversation: format!("{}/{chat_id}", self.session),
+            seq: *seq,
+            duration_ms: duration.as_millis() as u64,
+            code,
+            calls,
+            result: result.as_ref().ok(),
+            error: result.as_ref().err(),
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        // one write per record, so concurrent appends don't interleave
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+struct Recorded {
+    tool: Arc<dyn Tool>,
+    calls: Calls,
+}
+
+impl Tool for Recorded {
+    fn name(&self) -> &str {
+        self.tool.name()
+    }
+
+    fn description(&self) -> &str {
+        self.tool.description()
+    }
+
+    fn params(&self) -> Vec<String> {
+        self.tool.params()
+    }
+
+    fn is_pure(&self) -> bool {
+        self.tool.is_pure()
+    }
+
+    fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+        let call = ToolCall {
+            name: self.tool.name().to_string(),
+            args: args.clone(),
+        };
+        let result = self.tool.call(args);
+        let (outcome, error) = match &result {
+            Ok(_) => ("ok", None),
+            Err(err) if err.downcast_ref::<Denied>().is_some() => ("denied", Some(err.to_string())),
+            Err(err) => ("error", Some(format!("{err:#}"))),
+        };
+        self.calls.lock().unwrap().push(CallRecord {
+            call,
+            outcome,
+            error,
+        });
+        result
+    }
+}
+
+/// An RFC 3339 time, or a date meaning its local midnight
+fn parse_time(s: &str) -> anyhow::Result<DateTime<FixedOffset>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time);
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| anyhow::anyhow!("`{s}` is neither an RFC 3339 time nor a YYYY-MM-DD date"))?;
+    let time = Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap_or_default())
+        .earliest()
+        .ok_or_else(|| anyhow::anyhow!("`{s}` does not exist in the local time zone"))?;
+    Ok(time.fixed_offset())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    /// records where this tool was called
+    pub tool: Option<String>,
+    pub since: Option<String>,
+    /// exclusive
+    pub until: Option<String>,
+}
+
+/// Write the records of the log at `path` that match `filter` to `out`.
+pub fn query(path: &Path, filter: &AuditFilter, out: &mut impl Write) -> anyhow::Result<()> {
+    let since = filter.since.as_deref().map(parse_time).transpose()?;
+    let until = filter.until.as_deref().map(parse_time).transpose()?;
+    let file = File::open(path).with_context(|| format!("audit log `{}`", path.display()))?;
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Value =
+            serde_json::from_str(&line).with_context(|| format!("line {}", i + 1))?;
+        let time = record["time"]
+            .as_str()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok());
+        if since.is_some_and(|since| time.map_or(true, |time| time < since))
+            || until.is_some_and(|until| time.map_or(true, |time| time >= until))
+        {
+            continue;
+        }
+        if let Some(tool) = &filter.tool {
+            let called = record["calls"]
+                .as_array()
+                .is_some_and(|calls| calls.iter().any(|call| call["name"] == tool.as_str()));
+            if !called {
+                continue;
+            }
+        }
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    struct Echo;
+
+    impl Tool for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+            Ok(args.into())
+        }
+    }
+
+    fn lines(path: &Path, filter: &AuditFilter) -> Vec<Value> {
+        let mut out = Vec::new();
+        query(path, filter, &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn filter(tool: Option<&str>, since: Option<&str>, until: Option<&str>) -> AuditFilter {
+        AuditFilter {
+            tool: tool.map(str::to_string),
+            since: since.map(str::to_string),
+            until: until.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn records_have_the_script_and_its_calls() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let mut log = AuditLog::open(&AuditConfig { path: path.clone() }).unwrap();
+        let tools = log.record_calls(vec![Arc::new(Echo) as Arc<dyn Tool>]);
+
+        tools[0].call(vec![1.into()]).unwrap();
+        let ok = Ok(json!([1]));
+        log.write("lua", 3, "echo(1)", Duration::from_millis(5), &ok)
+            .unwrap();
+        let err = Err(ScriptError::from("boom".to_string()));
+        log.write("lua", 3, "error('boom')", Duration::ZERO, &err)
+            .unwrap();
+
+        let records = lines(&path, &AuditFilter::default());
+        assert_eq!(records.len(), 2);
+        let first = &records[0];
+        assert_eq!(first["engine"], "lua");
+        assert!(first["conversation"].as_str().unwrap().ends_with("/3"));
+        assert_eq!(first["seq"], 1);
+        assert_eq!(first["duration_ms"], 5);
+        assert_eq!(first["code"], "echo(1)");
+        assert_eq!(
+            first["calls"],
+            json!([{ "name": "echo", "args": [1], "outcome": "ok" }])
+        );
+        assert_eq!(first["result"], json!([1]));
+        assert!(DateTime::parse_from_rfc3339(first["time"].as_str().unwrap()).is_ok());
+
+        let second = &records[1];
+        assert_eq!(second["seq"], 2);
+        assert_eq!(second["calls"], json!([]));
+        assert!(second.get("result").is_none());
+        assert_eq!(second["error"]["error"], "boom");
+    }
+
+    #[test]
+    fn queries_filter_by_tool_and_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let record = |time: &str, tool: &str| {
+            json!({ "time": time, "code": time, "calls": [{ "name": tool }] }).to_string()
+        };
+        let log = [
+            record("2024-06-30T23:00:00+00:00", "send_sms"),
+            record("2024-07-01T12:00:00+00:00", "read_file"),
+            record("2024-07-02T00:00:00+00:00", "send_sms"),
+        ];
+        std::fs::write(&path, log.join("\n") + "\n\n").unwrap();
+        let codes = |filter: AuditFilter| -> Vec<String> {
+            lines(&path, &filter)
+                .iter()
+                .map(|record| record["code"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(codes(filter(None, None, None)).len(), 3);
+        assert_eq!(
+            codes(filter(Some("send_sms"), None, None)),
+            ["2024-06-30T23:00:00+00:00", "2024-07-02T00:00:00+00:00"]
+        );
+        assert_eq!(
+            codes(filter(None, Some("2024-07-01T00:00:00Z"), None)).len(),
+            2
+        );
+        // until is exclusive
+        assert_eq!(
+            codes(filter(None, None, Some("2024-07-02T00:00:00+00:00"))).len(),
+            2
+        );
+        assert_eq!(
+            codes(filter(
+                Some("read_file"),
+                Some("2024-07-01T12:00:00Z"),
+                Some("2024-07-01T12:00:01Z")
+            )),
+            ["2024-07-01T12:00:00+00:00"]
+        );
+        assert!(codes(filter(Some("nope"), None, None)).is_empty());
+    }
+
+    #[test]
+    fn dates_are_local_midnight() {
+        let time = parse_time("2024-07-01").unwrap();
+        let midnight = Local
+            .with_ymd_and_hms(2024, 7, 1, 0, 0, 0)
+            .earliest()
+            .unwrap();
+        assert_eq!(time, midnight.fixed_offset());
+        assert!(parse_time("2024-07-01T12:00:00+02:00").is_ok());
+        let err = parse_time("July 1st").unwrap_err();
+        assert!(err.to_string().contains("YYYY-MM-DD"), "{err}");
+    }
+}
@@ -0,0 +1,145 @@
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use super::{Tool, Tools};
+
+struct FnTool {
+    name: &'static str,
+    description: &'static str,
+    params: &'static [&'static str],
+    pure: bool,
+    f: fn(&[Value]) -> anyhow::Result<Value>,
+}
+
+impl Tool for FnTool {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn params(&self) -> Vec<String> {
+        self.params.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn is_pure(&self) -> bool {
+        self.pure
+    }
+
+    fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+        (self.f)(&args)
+    }
+}
+
+pub(super) fn str_arg(args: &[Value], i: usize, name: &str) -> anyhow::Result<String> {
+    match args.get(i) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(Value::Number(n)) => Ok(n.to_string()),
+        _ => Err(anyhow::anyhow!(
+            "argument #{} `{name}` must be a string",
+            i + 1
+        )),
+    }
+}
+
+fn int_arg(args: &[Value], i: usize, name: &str) -> anyhow::Result<i64> {
+    match args.get(i) {
+        Some(Value::Number(n)) if n.is_i64() => Ok(n.as_i64().unwrap_or_default()),
+        Some(Value::String(s)) if s.parse::<i64>().is_ok() => Ok(s.parse()?),
+        _ => Err(anyhow::anyhow!(
+            "argument #{} `{name}` must be an integer",
+            i + 1
+        )),
+    }
+}
+
+fn send_sms(args: &[Value]) -> anyhow::Result<Value> {
+    let number = str_arg(args, 0, "number")?;
+    let sms_msg = str_arg(args, 1, "sms_msg")?;
+    Ok(serde_json::json!({
+        "status":"ok",
+        "number":number,
+        "sms_msg":sms_msg
+    }))
+}
+
+fn send_msg(args: &[Value]) -> anyhow::Result<Value> {
+    let room_id = int_arg(args, 0, "room_id")?;
+    let message = str_arg(args, 1, "message")?;
+    Ok(serde_json::json!({
+        "status":"ok",
+        "room_id":room_id,
+        "message":message
+    }))
+}
+
+fn remember(args: &[Value]) -> anyhow::Result<Value> {
+    let _time = int_arg(args, 0, "time")?;
+    let _text = str_arg(args, 1, "text")?;
+    Ok(serde_json::json!({
+        "status":"ok"
+    }))
+}
+
+fn get_weather(_: &[Value]) -> anyhow::Result<Value> {
+    Ok(serde_json::json!({
+        "status":"ok",
+        "temp":"18",
+        "weather":"雨"
+    }))
+}
+
+fn get_current_time(_: &[Value]) -> anyhow::Result<Value> {
+    let time = chrono::Local::now().to_rfc3339();
+    Ok(serde_json::json!({
+        "status":"ok",
+        "time": time
+    }))
+}
+
+pub fn builtin_tools() -> Tools {
+    let tools = [
+        FnTool {
+            name: "send_sms",
+            description: "Send a text message to a phone number",
+            params: &["number", "sms_msg"],
+            pure: false,
+            f: send_sms,
+        },
+        FnTool {
+            name: "send_msg",
+            description: "Send a message to a chat room",
+            params: &["room_id", "message"],
+            pure: false,
+            f: send_msg,
+        },
+        FnTool {
+            name: "remember",
+            description: "Remind the user of `text` at unix time `time`",
+            params: &["time", "text"],
+            pure: false,
+            f: remember,
+        },
+        FnTool {
+            name: "get_weather",
+            description: "Current weather",
+            params: &[],
+            pure: true,
+            f: get_weather,
+        },
+        FnTool {
+            name: "get_current_time",
+            description: "Current local time in RFC 3339",
+            params: &[],
+            pure: true,
+            f: get_current_time,
+        },
+    ];
+    tools
+        .into_iter()
+        .map(|t| Arc::new(t) as Arc<dyn Tool>)
+        .collect()
+}
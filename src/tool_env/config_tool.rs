@@ -0,0 +1,539 @@
+//! Tools declared in project.toml that run a local command or send an HTTP request.
+
+use std::{
+    collections::HashMap,
+    io::Read,
+    process::{Command, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde_json::Value;
+use wait_timeout::ChildExt;
+
+use super::{Tool, Tools};
+
+/// A tool backed by a command line or a URL. `{param}` in `command`, `url`
+/// and `body` is replaced by the argument of that name.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ToolConfig {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub params: Vec<String>,
+    #[serde(default)]
+    pub pure: bool,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// the only values accepted for a parameter
+    #[serde(default)]
+    pub allow: HashMap<String, Vec<String>>,
+
+    /// program and arguments, run without a shell
+    pub command: Option<Vec<String>>,
+
+    pub url: Option<String>,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// JSON body, a string that is exactly `{param}` keeps the argument's type.
+    /// Requests other than GET send all arguments as an object when it is not set.
+    pub body: Option<Value>,
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+/// `(start, name, end)` of the next `{name}`, other braces are plain text.
+fn next_placeholder(s: &str) -> Option<(usize, &str, usize)> {
+    let mut from = 0;
+    while let Some(i) = s[from..].find('{') {
+        let start = from + i;
+        let name = &s[start + 1..];
+        let len = name
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(name.len());
+        if len > 0 && name[len..].starts_with('}') {
+            return Some((start, &name[..len], start + len + 2));
+        }
+        from = start + 1;
+    }
+    None
+}
+
+fn placeholders(template: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some((_, name, end)) = next_placeholder(rest) {
+        names.push(name);
+        rest = &rest[end..];
+    }
+    names
+}
+
+/// Single pass, so an argument containing `{name}` is not expanded again.
+fn render(template: &str, args: &HashMap<&str, Value>, encode: fn(&str) -> String) -> String {
+    let mut s = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some((start, name, end)) = next_placeholder(rest) {
+        s.push_str(&rest[..start]);
+        match args.get(name) {
+            Some(Value::String(v)) => s.push_str(&encode(v)),
+            Some(v) => s.push_str(&encode(&v.to_string())),
+            None => s.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    s.push_str(rest);
+    s
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+fn render_body(body: &Value, args: &HashMap<&str, Value>) -> Value {
+    match body {
+        Value::String(s) => {
+            if let [name] = placeholders(s).as_slice() {
+                if s.len() == name.len() + 2 {
+                    return args.get(name).cloned().unwrap_or_default();
+                }
+            }
+            Value::String(render(s, args, str::to_string))
+        }
+        Value::Array(a) => a.iter().map(|v| render_body(v, args)).collect(),
+        Value::Object(o) => o
+            .iter()
+            .map(|(k, v)| (k.clone(), render_body(v, args)))
+            .collect(),
+        v => v.clone(),
+    }
+}
+
+/// Command output and response bodies are JSON when they parse as JSON.
+fn output_value(output: &str) -> Value {
+    let output = output.trim();
+    serde_json::from_str(output).unwrap_or_else(|_| output.into())
+}
+
+struct ConfigTool {
+    config: ToolConfig,
+    agent: ureq::Agent,
+}
+
+impl ConfigTool {
+    fn new(config: ToolConfig) -> anyhow::Result<Self> {
+        let templates: Vec<&str> = match (&config.command, &config.url) {
+            (Some(command), None) => {
+                let program = command.first().context("`command` is empty")?;
+                anyhow::ensure!(
+                    placeholders(program).is_empty(),
+                    "the program `{program}` can't be templated"
+                );
+                command.iter().map(String::as_str).collect()
+            }
+            (None, Some(url)) => {
+                // arguments may only go into the path and the query, never the host
+                let host_end = url
+                    .find("://")
+                    .map(|i| i + 3)
+                    .context("`url` needs a scheme")?;
+                let path_start = url[host_end..]
+                    .find('/')
+                    .map_or(url.len(), |i| host_end + i);
+                anyhow::ensure!(
+                    !url[..path_start].contains('{'),
+                    "the host of `{url}` can't be templated"
+                );
+                vec![url.as_str()]
+            }
+            _ => anyhow::bail!("set either `command` or `url`"),
+        };
+        for template in templates {
+            for name in placeholders(template) {
+                anyhow::ensure!(
+                    config.params.iter().any(|p| p == name),
+                    "`{{{name}}}` is not a parameter"
+                );
+            }
+        }
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build();
+        Ok(ConfigTool { config, agent })
+    }
+
+    fn arguments(&self, args: Vec<Value>) -> anyhow::Result<HashMap<&str, Value>> {
+        let params = &self.config.params;
+        anyhow::ensure!(
+            args.len() == params.len(),
+            "`{}` takes {} arguments ({}), got {}",
+            self.config.name,
+            params.len(),
+            params.join(", "),
+            args.len()
+        );
+        let args: HashMap<&str, Value> = params.iter().map(String::as_str).zip(args).collect();
+        for (name, allowed) in &self.config.allow {
+            let value = match args.get(name.as_str()) {
+                Some(Value::String(v)) => v.clone(),
+                Some(v) => v.to_string(),
+                None => continue,
+            };
+            anyhow::ensure!(
+                allowed.contains(&value),
+                "argument `{name}` must be one of {}",
+                allowed.join(", ")
+            );
+        }
+        Ok(args)
+    }
+
+    fn run_command(
+        &self,
+        command: &[String],
+        args: &HashMap<&str, Value>,
+    ) -> anyhow::Result<Value> {
+        for (name, value) in args {
+            if let Value::String(v) = value {
+                // or it would be read as an option of the program
+                anyhow::ensure!(
+                    !v.starts_with('-') || v.parse::<f64>().is_ok(),
+                    "argument `{name}` can't start with `-`"
+                );
+            }
+        }
+        let argv: Vec<String> = command[1..]
+            .iter()
+            .map(|arg| render(arg, args, str::to_string))
+            .collect();
+
+        let mut child = Command::new(&command[0])
+            .args(&argv)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run `{}`", command[0]))?;
+
+        // drain the pipes while waiting, a full pipe would block the child
+        let mut stdout = child.stdout.take().context("no stdout")?;
+        let mut stderr = child.stderr.take().context("no stderr")?;
+        let stdout = std::thread::spawn(move || {
+            let mut buf = String::new();
+            let _ = stdout.read_to_string(&mut buf);
+            buf
+        });
+        let stderr = std::thread::spawn(move || {
+            let mut buf = String::new();
+            let _ = stderr.read_to_string(&mut buf);
+            buf
+        });
+
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let Some(status) = child.wait_timeout(timeout)? else {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("`{}` timed out after {timeout:?}", command[0]);
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        if !status.success() {
+            anyhow::bail!("`{}` failed ({status}): {}", command[0], stderr.trim());
+        }
+        Ok(output_value(&stdout))
+    }
+
+    fn send_request(&self, url: &str, args: &HashMap<&str, Value>) -> anyhow::Result<Value> {
+        let url = render(url, args, percent_encode);
+        let mut request = self.agent.request(&self.config.method, &url);
+        for (name, value) in &self.config.headers {
+            request = request.set(name, value);
+        }
+
+        let body = match &self.config.body {
+            Some(body) => Some(render_body(body, args)),
+            None if !self.config.method.eq_ignore_ascii_case("GET") => Some(Value::Object(
+                args.iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect(),
+            )),
+            None => None,
+        };
+        let response = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        };
+
+        match response {
+            Ok(response) => Ok(output_value(&response.into_string()?)),
+            Err(ureq::Error::Status(code, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                anyhow::bail!("HTTP {code}: {}", body.trim())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Tool for ConfigTool {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn description(&self) -> &str {
+        &self.config.description
+    }
+
+    fn params(&self) -> Vec<String> {
+        self.config.params.clone()
+    }
+
+    fn is_pure(&self) -> bool {
+        self.config.pure
+    }
+
+    fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+        let args = self.arguments(args)?;
+        match (&self.config.command, &self.config.url) {
+            (Some(command), _) => self.run_command(command, &args),
+            (_, Some(url)) => self.send_request(url, &args),
+            _ => unreachable!("checked in ConfigTool::new"),
+        }
+    }
+}
+
+pub fn load_tools(configs: &[ToolConfig]) -> anyhow::Result<Tools> {
+    configs
+        .iter()
+        .map(|config| {
+            let tool = ConfigTool::new(config.clone())
+                .with_context(|| format!("tool `{}`", config.name))?;
+            Ok(Arc::new(tool) as Arc<dyn Tool>)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn tool(toml: &str) -> anyhow::Result<ConfigTool> {
+        ConfigTool::new(toml::from_str(toml).unwrap())
+    }
+
+    fn args<'a>(pairs: &[(&'a str, Value)]) -> HashMap<&'a str, Value> {
+        pairs.iter().cloned().collect()
+    }
+
+    #[test]
+    fn placeholders_are_names_in_braces() {
+        assert_eq!(
+            placeholders("{a} and {b_2}, not {} or { c } or {d-e}"),
+            ["a", "b_2"]
+        );
+    }
+
+    #[test]
+    fn render_substitutes_once() {
+        let values = args(&[("city", "{n}".into()), ("n", 3.into())]);
+        assert_eq!(
+            render("{city}/{n}/{missing}/{", &values, str::to_string),
+            "{n}/3/{missing}/{"
+        );
+        let values = args(&[("q", "a b&c/é".into())]);
+        assert_eq!(
+            render("?q={q}", &values, percent_encode),
+            "?q=a%20b%26c%2F%C3%A9"
+        );
+    }
+
+    #[test]
+    fn body_placeholders_keep_their_type() {
+        let body = json!({ "n": "{n}", "text": "n is {n}", "list": ["{tags}"], "fixed": true });
+        let values = args(&[("n", 3.into()), ("tags", json!(["a", "b"]))]);
+        assert_eq!(
+            render_body(&body, &values),
+            json!({ "n": 3, "text": "n is 3", "list": [["a", "b"]], "fixed": true })
+        );
+    }
+
+    #[test]
+    fn declarations_are_checked() {
+        let err = |toml| tool(toml).err().unwrap().to_string();
+        assert!(err(r#"name = "t""#).contains("either `command` or `url`"));
+        assert!(err(r#"name = "t"
+command = ["{p}"]
+params = ["p"]"#)
+        .contains("can't be templated"));
+        assert!(err(r#"name = "t"
+url = "http://{host}/x"
+params = ["host"]"#)
+        .contains("host"));
+        assert!(err(r#"name = "t"
+command = ["echo", "{nope}"]"#)
+        .contains("`{nope}` is not a parameter"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_get_their_arguments() {
+        let echo = tool(
+            r#"name = "greet"
+command = ["echo", "hello {who}", "{n}"]
+params = ["who", "n"]
+allow = { who = ["world", "{n}"] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            echo.call(vec!["world".into(), 2.into()]).unwrap(),
+            json!("hello world 2")
+        );
+        // not expanded again
+        assert_eq!(
+            echo.call(vec!["{n}".into(), 2.into()]).unwrap(),
+            json!("hello {n} 2")
+        );
+        assert!(echo.call(vec!["moon".into(), 2.into()]).is_err());
+        assert!(echo.call(vec!["world".into()]).is_err());
+        assert!(echo.call(vec!["world".into(), "-n".into()]).is_err());
+    }
+
+    /// the declared command is the only one a script can run
+    #[cfg(unix)]
+    #[test]
+    fn scripts_only_run_declared_commands() {
+        use crate::tool_env::{lua::LuaEngine, ScriptEngin};
+
+        let echo = tool(
+            r#"name = "greet"
+command = ["echo", "hello {who}"]
+params = ["who"]"#,
+        )
+        .unwrap();
+        let mut lua = LuaEngine::new(vec![Arc::new(echo) as Arc<dyn Tool>]).unwrap();
+        assert_eq!(
+            lua.eval("return greet('world')").unwrap(),
+            json!("hello world")
+        );
+        for code in [
+            "return os.execute('true')",
+            "return io.popen('true'):read('a')",
+            "return require('os').execute('true')",
+        ] {
+            assert!(lua.eval(code).is_err(), "{code}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_time_out() {
+        let sleep = tool(
+            r#"name = "nap"
+command = ["sleep", "5"]
+timeout_secs = 1"#,
+        )
+        .unwrap();
+        let start = std::time::Instant::now();
+        let err = sleep.call(vec![]).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_commands_report_stderr() {
+        let fail = tool(
+            r#"name = "fail"
+command = ["sh", "-c", "echo oops >&2; exit 3"]"#,
+        )
+        .unwrap();
+        let err = fail.call(vec![]).unwrap_err().to_string();
+        assert!(err.contains("oops"), "{err}");
+    }
+
+    #[test]
+    fn requests_get_their_arguments() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // answers with the request line and the body it got
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            std::io::Read::read_exact(&mut reader, &mut body).unwrap();
+            let response = json!({
+                "line": request_line.trim(),
+                "body": serde_json::from_slice::<Value>(&body).unwrap_or_default(),
+            })
+            .to_string();
+            write!(
+                &stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                response.len()
+            )
+            .unwrap();
+        });
+
+        let post = tool(&format!(
+            r#"name = "post"
+url = "http://127.0.0.1:{port}/rooms/{{room}}?q={{q}}"
+method = "POST"
+params = ["room", "q", "n"]
+body = {{ count = "{{n}}" }}"#
+        ))
+        .unwrap();
+        let result = post
+            .call(vec!["a/b".into(), "x y".into(), 2.into()])
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "line": "POST /rooms/a%2Fb?q=x%20y HTTP/1.1",
+                "body": { "count": 2 },
+            })
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Script errors in one shape for every engine, so the model can see where it went wrong.
+
+use std::fmt;
+
+use serde_json::Value;
+
+use super::Tools;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The script doesn't parse
+    Syntax,
+    /// Raised while the script was running
+    Runtime,
+    /// A call to a function that isn't defined
+    UnknownFunction,
+    /// A tool refused its arguments or failed
+    Tool,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ScriptError {
+    pub kind: ErrorKind,
+    #[serde(rename = "error")]
+    pub message: String,
+    /// 1-based, in the evaluated code. Not every engine reports it: js has it
+    /// for syntax errors only, lua has no column.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    /// The line of code at `line`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl ScriptError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        ScriptError {
+            kind,
+            message: message.into(),
+            line: None,
+            column: None,
+            source: None,
+            hint: None,
+        }
+    }
+
+    /// Point at `line` of `code`. A line the code doesn't have is dropped,
+    /// it belongs to a function defined by an earlier script.
+    pub fn at(mut self, code: &str, line: Option<usize>, column: Option<usize>) -> Self {
+        let Some(source) = line.and_then(|line| code.lines().nth(line.checked_sub(1)?)) else {
+            return self;
+        };
+        self.line = line;
+        self.column = column;
+        self.source = Some(source.trim_end().to_string());
+        self
+    }
+
+    /// Add the hint for the kind, the available functions or the tool's parameters.
+    pub fn with_hint(mut self, tools: &Tools, variables: &[(String, String)]) -> Self {
+        self.hint = match self.kind {
+            ErrorKind::UnknownFunction => Some(format!(
+                "available functions: {}",
+                functions(tools, variables).join(", ")
+            )),
+            ErrorKind::Tool => failed_tool(tools, &self.message)
+                .map(|(name, params)| format!("usage: {name}({})", params.join(", "))),
+            _ => None,
+        };
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut value = serde_json::json!({ "status": "error" });
+        if let (Value::Object(map), Ok(Value::Object(err))) =
+            (&mut value, serde_json::to_value(self))
+        {
+            map.extend(err);
+        }
+        value
+    }
+}
+
+impl From<String> for ScriptError {
+    fn from(message: String) -> Self {
+        ScriptError::new(ErrorKind::Runtime, message)
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, " (line {line}, column {column})"),
+            (Some(line), None) => write!(f, " (line {line})"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Tool errors are raised as `name: error` by the engines
+pub fn tool_message(name: &str, err: &anyhow::Error) -> String {
+    format!("{name}: {err:#}")
+}
+
+/// The tool and its parameters, when `message` was raised by a tool
+fn failed_tool(tools: &Tools, message: &str) -> Option<(String, Vec<String>)> {
+    let (name, _) = message.split_once(": ")?;
+    let tool = tools.iter().find(|tool| tool.name() == name)?;
+    Some((name.to_string(), tool.params()))
+}
+
+pub fn is_tool_error(tools: &Tools, message: &str) -> bool {
+    failed_tool(tools, message).is_some()
+}
+
+/// `name(params)` of the tools and the functions the scripts defined
+fn functions(tools: &Tools, variables: &[(String, String)]) -> Vec<String> {
+    let tools = tools
+        .iter()
+        .map(|tool| format!("{}({})", tool.name(), tool.params().join(", ")));
+    let defined = variables.iter().filter_map(|(name, value)| {
+        if value == "function" {
+            Some(format!("{name}(...)"))
+        } else {
+            value
+                .strip_prefix("fn")
+                .map(|params| format!("{name}{params}"))
+        }
+    });
+    tools.chain(defined).collect()
+}
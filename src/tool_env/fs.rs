@@ -0,0 +1,408 @@
+//! File tools confined to one directory.
+
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde_json::Value;
+
+use super::{builtin::str_arg, Tool, Tools};
+
+const MAX_SEARCH_MATCHES: usize = 100;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct FsConfig {
+    pub root: PathBuf,
+    /// globs relative to `root`, a path must match one of them
+    #[serde(default = "default_allow")]
+    pub allow: Vec<String>,
+    /// globs relative to `root`, checked before `allow`
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// bytes, for reading and writing
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    #[serde(default)]
+    pub writable: bool,
+}
+
+fn default_allow() -> Vec<String> {
+    vec!["**".to_string()]
+}
+
+fn default_max_file_size() -> u64 {
+    256 * 1024
+}
+
+fn glob_set(globs: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    Ok(builder.build()?)
+}
+
+struct Sandbox {
+    root: PathBuf,
+    allow: GlobSet,
+    deny: GlobSet,
+    max_file_size: u64,
+    writable: bool,
+}
+
+impl Sandbox {
+    fn new(config: &FsConfig) -> anyhow::Result<Self> {
+        let root = config
+            .root
+            .canonicalize()
+            .with_context(|| format!("root `{}`", config.root.display()))?;
+        Ok(Sandbox {
+            root,
+            allow: glob_set(&config.allow)?,
+            deny: glob_set(&config.deny)?,
+            max_file_size: config.max_file_size,
+            writable: config.writable,
+        })
+    }
+
+    fn is_visible(&self, relative: &Path) -> bool {
+        !self.deny.is_match(relative) && self.allow.is_match(relative)
+    }
+
+    /// `.git/**` hides `.git` itself too
+    fn is_denied_dir(&self, relative: &Path) -> bool {
+        self.deny.is_match(relative) || self.deny.is_match(relative.join("_"))
+    }
+
+    fn is_hidden(&self, relative: &Path, is_dir: bool) -> bool {
+        if relative.as_os_str().is_empty() {
+            false
+        } else if is_dir {
+            self.is_denied_dir(relative)
+        } else {
+            !self.is_visible(relative)
+        }
+    }
+
+    /// `full` relative to the root once the symlinks of the part that exists are
+    /// followed, what the globs are checked against: `notes.txt -> .env` is `.env`
+    fn real_relative(&self, full: &Path, path: &str) -> anyhow::Result<PathBuf> {
+        let mut existing = full;
+        let mut missing = Vec::new();
+        // a dangling link exists too, it fails to canonicalize rather than being written through
+        while existing.symlink_metadata().is_err() {
+            missing.extend(existing.file_name());
+            existing = existing.parent().unwrap_or(&self.root);
+        }
+        let real = existing
+            .canonicalize()
+            .with_context(|| format!("`{path}` is a broken link"))?;
+        let relative = real
+            .strip_prefix(&self.root)
+            .map_err(|_| anyhow::anyhow!("`{path}` is outside the root"))?;
+        Ok(missing
+            .into_iter()
+            .rev()
+            .fold(relative.to_path_buf(), |real, c| real.join(c)))
+    }
+
+    /// Resolve `path`, relative to the root, refusing anything outside of it
+    /// or not matched by the globs. The path doesn't have to exist yet.
+    fn resolve(&self, path: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+        let mut relative = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(c) => relative.push(c),
+                Component::CurDir => {}
+                _ => anyhow::bail!("`{path}` must be a relative path inside the root"),
+            }
+        }
+
+        let full = self.root.join(&relative);
+        // a symlink may point outside, or at a denied path under another name
+        let real = self.real_relative(&full, path)?;
+        let is_dir = full.is_dir();
+        anyhow::ensure!(
+            !self.is_hidden(&relative, is_dir) && !self.is_hidden(&real, is_dir),
+            "`{path}` is not accessible"
+        );
+        Ok((full, relative))
+    }
+
+    fn read_text(&self, full: &Path, path: &str) -> anyhow::Result<String> {
+        let size = full
+            .metadata()
+            .with_context(|| format!("`{path}` not found"))?
+            .len();
+        anyhow::ensure!(
+            size <= self.max_file_size,
+            "`{path}` is {size} bytes, the limit is {}",
+            self.max_file_size
+        );
+        let bytes = std::fs::read(full)?;
+        String::from_utf8(bytes).map_err(|_| anyhow::anyhow!("`{path}` is not a text file"))
+    }
+
+    fn read_file(&self, args: &[Value]) -> anyhow::Result<Value> {
+        let path = str_arg(args, 0, "path")?;
+        let (full, _) = self.resolve(&path)?;
+        Ok(self.read_text(&full, &path)?.into())
+    }
+
+    fn write_file(&self, args: &[Value]) -> anyhow::Result<Value> {
+        anyhow::ensure!(self.writable, "the filesystem is read only");
+        let path = str_arg(args, 0, "path")?;
+        let content = str_arg(args, 1, "content")?;
+        anyhow::ensure!(
+            content.len() as u64 <= self.max_file_size,
+            "content is {} bytes, the limit is {}",
+            content.len(),
+            self.max_file_size
+        );
+        let (full, _) = self.resolve(&path)?;
+        if let Some(parent) = full.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&full, &content)?;
+        Ok(serde_json::json!({
+            "status": "ok",
+            "path": path,
+            "bytes": content.len()
+        }))
+    }
+
+    fn list_dir(&self, args: &[Value]) -> anyhow::Result<Value> {
+        let path = match args.first() {
+            Some(_) => str_arg(args, 0, "path")?,
+            None => ".".to_string(),
+        };
+        let (full, relative) = self.resolve(&path)?;
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&full).with_context(|| format!("`{path}` not found"))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let relative = relative.join(entry.file_name());
+            let is_dir = entry.path().is_dir();
+            let mut hidden = self.is_hidden(&relative, is_dir);
+            if file_type.is_symlink() {
+                let path = relative.to_string_lossy();
+                hidden |= match self.real_relative(&entry.path(), &path) {
+                    Ok(real) => self.is_hidden(&real, is_dir),
+                    Err(_) => true,
+                };
+            }
+            if hidden {
+                continue;
+            }
+            let kind = if file_type.is_dir() {
+                "dir"
+            } else if file_type.is_symlink() {
+                "link"
+            } else {
+                "file"
+            };
+            let mut item = serde_json::json!({
+                "name": entry.file_name().to_string_lossy(),
+                "type": kind,
+            });
+            if file_type.is_file() {
+                item["size"] = entry.metadata()?.len().into();
+            }
+            entries.push(item);
+        }
+        entries.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+        Ok(entries.into())
+    }
+
+    fn search_dir(&self, dir: &Path, relative: &Path, text: &str, matches: &mut Vec<Value>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if matches.len() >= MAX_SEARCH_MATCHES {
+                return;
+            }
+            let relative = relative.join(entry.file_name());
+            // links are not followed, they may lead to a denied path or out of the root
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_symlink() {
+                continue;
+            }
+            if file_type.is_dir() && !self.is_denied_dir(&relative) {
+                self.search_dir(&entry.path(), &relative, text, matches);
+            } else if file_type.is_file() && self.is_visible(&relative) {
+                let path = relative.to_string_lossy();
+                let Ok(content) = self.read_text(&entry.path(), &path) else {
+                    continue;
+                };
+                for (i, line) in content.lines().enumerate() {
+                    if line.contains(text) {
+                        matches.push(serde_json::json!({
+                            "path": path,
+                            "line": i + 1,
+                            "text": line.trim(),
+                        }));
+                        if matches.len() >= MAX_SEARCH_MATCHES {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn search_files(&self, args: &[Value]) -> anyhow::Result<Value> {
+        let text = str_arg(args, 0, "text")?;
+        let path = match args.get(1) {
+            Some(_) => str_arg(args, 1, "path")?,
+            None => ".".to_string(),
+        };
+        let (full, relative) = self.resolve(&path)?;
+        let mut matches = Vec::new();
+        self.search_dir(&full, &relative, &text, &mut matches);
+        Ok(matches.into())
+    }
+}
+
+struct FsTool {
+    name: &'static str,
+    description: &'static str,
+    params: &'static [&'static str],
+    pure: bool,
+    f: fn(&Sandbox, &[Value]) -> anyhow::Result<Value>,
+    sandbox: Arc<Sandbox>,
+}
+
+impl Tool for FsTool {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn params(&self) -> Vec<String> {
+        self.params.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn is_pure(&self) -> bool {
+        self.pure
+    }
+
+    fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+        (self.f)(&self.sandbox, &args)
+    }
+}
+
+pub fn fs_tools(config: &FsConfig) -> anyhow::Result<Tools> {
+    let sandbox = Arc::new(Sandbox::new(config)?);
+    let tools = [
+        FsTool {
+            name: "read_file",
+            description: "Read a text file",
+            params: &["path"],
+            pure: true,
+            f: Sandbox::read_file,
+            sandbox: sandbox.clone(),
+        },
+        FsTool {
+            name: "write_file",
+            description: "Create or overwrite a text file",
+            params: &["path", "content"],
+            pure: false,
+            f: Sandbox::write_file,
+            sandbox: sandbox.clone(),
+        },
+        FsTool {
+            name: "list_dir",
+            description: "List a directory, the project root by default",
+            params: &["path"],
+            pure: true,
+            f: Sandbox::list_dir,
+            sandbox: sandbox.clone(),
+        },
+        FsTool {
+            name: "search_files",
+            description: "Find the lines containing `text` in the files under `path`",
+            params: &["text", "path"],
+            pure: true,
+            f: Sandbox::search_files,
+            sandbox,
+        },
+    ];
+    Ok(tools
+        .into_iter()
+        .map(|t| Arc::new(t) as Arc<dyn Tool>)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox(root: &Path) -> Sandbox {
+        Sandbox::new(&FsConfig {
+            root: root.to_path_buf(),
+            allow: default_allow(),
+            deny: vec![".env".to_string(), ".git/**".to_string()],
+            max_file_size: default_max_file_size(),
+            writable: true,
+        })
+        .unwrap()
+    }
+
+    fn names(listing: Value) -> Vec<String> {
+        listing
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn denied_paths_are_refused() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join(".env"), "SECRET=1").unwrap();
+        std::fs::write(root.path().join("notes.txt"), "hi").unwrap();
+        let sandbox = sandbox(root.path());
+
+        assert_eq!(sandbox.read_file(&["notes.txt".into()]).unwrap(), "hi");
+        assert!(sandbox.read_file(&[".env".into()]).is_err());
+        assert!(sandbox.read_file(&["../x".into()]).is_err());
+        assert_eq!(names(sandbox.list_dir(&[]).unwrap()), ["notes.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_to_denied_paths_are_refused() {
+        use std::os::unix::fs::symlink;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join(".env"), "SECRET=1").unwrap();
+        std::fs::create_dir(root.path().join(".git")).unwrap();
+        std::fs::write(root.path().join(".git/config"), "SECRET=2").unwrap();
+        symlink(root.path().join(".env"), root.path().join("notes.txt")).unwrap();
+        symlink(root.path().join(".git"), root.path().join("link")).unwrap();
+        symlink("/etc/hostname", root.path().join("outside")).unwrap();
+        symlink(root.path().join("gone"), root.path().join("dangling")).unwrap();
+        let sandbox = sandbox(root.path());
+
+        assert!(sandbox.read_file(&["notes.txt".into()]).is_err());
+        assert!(sandbox.read_file(&["link/config".into()]).is_err());
+        assert!(sandbox.list_dir(&["link".into()]).is_err());
+        assert!(sandbox.read_file(&["outside".into()]).is_err());
+        assert!(sandbox
+            .write_file(&["dangling".into(), "x".into()])
+            .is_err());
+        assert!(names(sandbox.list_dir(&[]).unwrap()).is_empty());
+        let matches = sandbox.search_files(&["SECRET".into()]).unwrap();
+        assert_eq!(matches, serde_json::json!([]));
+    }
+}
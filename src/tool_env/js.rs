@@ -0,0 +1,419 @@
+use std::collections::HashSet;
+
+use boa_engine::{
+    error::JsNativeErrorKind, js_string, Context, JsError, JsNativeError, JsResult, JsString,
+    JsValue, NativeFunction, Source,
+};
+
+use super::{
+    error::{self, ErrorKind, ScriptError},
+    SharedStdout, Tools,
+};
+
+const LOOP_ITERATION_LIMIT: u64 = 1_000_000;
+const RECURSION_LIMIT: usize = 256;
+const STACK_SIZE_LIMIT: usize = 64 * 1024;
+
+fn bind_tools(context: &mut Context, tools: &Tools) -> JsResult<()> {
+    for tool in tools {
+        let t = tool.clone();
+        // SAFETY: the closure only captures the tool, which holds no GC traced values.
+        let f = unsafe {
+            NativeFunction::from_closure(move |_, args, context| {
+                let args = args
+                    .iter()
+                    .map(|arg| to_json(arg, context))
+                    .collect::<JsResult<Vec<_>>>()?;
+                let r = t.call(args).map_err(|e| {
+                    JsNativeError::error().with_message(error::tool_message(t.name(), &e))
+                })?;
+                JsValue::from_json(&r, context)
+            })
+        };
+        context.register_global_callable(JsString::from(tool.name()), 0, f)?;
+    }
+    Ok(())
+}
+
+pub fn new_js(stdout: SharedStdout, tools: &Tools) -> JsResult<Context> {
+    let mut context = Context::default();
+
+    let limits = context.runtime_limits_mut();
+    limits.set_loop_iteration_limit(LOOP_ITERATION_LIMIT);
+    limits.set_recursion_limit(RECURSION_LIMIT);
+    limits.set_stack_size_limit(STACK_SIZE_LIMIT);
+
+    // SAFETY: the closure only captures the stdout buffer, which holds no GC traced values.
+    let print = unsafe {
+        NativeFunction::from_closure(move |_, args, context| {
+            let line = args
+                .iter()
+                .map(|v| Ok(v.to_string(context)?.to_std_string_escaped()))
+                .collect::<JsResult<Vec<_>>>()?
+                .join(" ");
+            stdout.borrow_mut().push(line);
+            Ok(JsValue::undefined())
+        })
+    };
+
+    context.register_global_callable(js_string!("print"), 0, print)?;
+    context.eval(Source::from_bytes("var console = { log: print };"))?;
+
+    bind_tools(&mut context, tools)?;
+
+    Ok(context)
+}
+
+/// `JsValue::to_json` panics on `undefined`, so go through `JSON.stringify` instead.
+fn to_json(value: &JsValue, context: &mut Context) -> JsResult<serde_json::Value> {
+    let stringify = context
+        .global_object()
+        .get(js_string!("JSON"), context)?
+        .as_object()
+        .ok_or_else(|| JsNativeError::typ().with_message("JSON is not an object"))?
+        .get(js_string!("stringify"), context)?;
+    let stringify = stringify
+        .as_callable()
+        .ok_or_else(|| JsNativeError::typ().with_message("JSON.stringify is not callable"))?;
+
+    let json = stringify.call(&JsValue::undefined(), std::slice::from_ref(value), context)?;
+    match json.as_string() {
+        Some(json) => serde_json::from_str(&json.to_std_string_escaped())
+            .map_err(|e| JsNativeError::typ().with_message(e.to_string()).into()),
+        // functions and undefined have no JSON form
+        None => Ok(serde_json::Value::Null),
+    }
+}
+
+/// boa puts the position of a syntax error at the end, `... at line 1, col 5`
+fn split_position(message: &str) -> (&str, Option<usize>, Option<usize>) {
+    let position = message
+        .rsplit_once(" at line ")
+        .and_then(|(message, position)| {
+            let (line, column) = position.split_once(", col ")?;
+            Some((message, line.parse().ok()?, column.parse().ok()?))
+        });
+    match position {
+        Some((message, line, column)) => (message, Some(line), Some(column)),
+        None => (message, None, None),
+    }
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// The identifier at `chars[*i..]` after any whitespace, moving `i` past it
+fn read_ident(chars: &[char], i: &mut usize) -> Option<String> {
+    while chars.get(*i).is_some_and(|c| c.is_whitespace()) {
+        *i += 1;
+    }
+    let start = *i;
+    while chars.get(*i).is_some_and(|&c| is_ident(c)) {
+        *i += 1;
+    }
+    (*i > start).then(|| chars[start..*i].iter().collect())
+}
+
+/// Names the top-level `let`, `const` and `class` of `code` declare. They live in the
+/// script scope, not on `globalThis`. Destructuring patterns are left out.
+fn lexical_names(code: &str) -> Vec<String> {
+    let chars: Vec<char> = code.chars().collect();
+    let mut names = Vec::new();
+    let mut depth = 0usize;
+    let mut statement_start = true;
+    // in a `let` or `const`, a comma at the top starts another declarator
+    let mut declaring = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            '"' | '\'' | '`' => {
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                statement_start = false;
+            }
+            '/' if next == Some('/') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            '/' if next == Some('*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            '{' | '(' | '[' => {
+                depth += 1;
+                statement_start = false;
+            }
+            '}' | ')' | ']' => {
+                depth = depth.saturating_sub(1);
+                statement_start = depth == 0 && c == '}';
+            }
+            ';' if depth == 0 => {
+                declaring = false;
+                statement_start = true;
+            }
+            '\n' if depth == 0 => statement_start = true,
+            ',' if depth == 0 && declaring => {
+                i += 1;
+                names.extend(read_ident(&chars, &mut i));
+                continue;
+            }
+            c if is_ident(c) => {
+                let word = read_ident(&chars, &mut i).unwrap_or_default();
+                if depth == 0
+                    && statement_start
+                    && matches!(word.as_str(), "let" | "const" | "class")
+                {
+                    names.extend(read_ident(&chars, &mut i));
+                    declaring = word != "class";
+                }
+                statement_start = false;
+                continue;
+            }
+            c if c.is_whitespace() => {}
+            _ => statement_start = false,
+        }
+        i += 1;
+    }
+    names
+}
+
+fn global_names(context: &mut Context) -> JsResult<Vec<String>> {
+    let names = context.eval(Source::from_bytes("Object.getOwnPropertyNames(globalThis)"))?;
+    match to_json(&names, context)? {
+        serde_json::Value::Array(names) => Ok(names
+            .into_iter()
+            .filter_map(|n| n.as_str().map(str::to_string))
+            .collect()),
+        _ => Ok(vec![]),
+    }
+}
+
+pub struct JsEngine {
+    context: Context,
+    builtins: HashSet<String>,
+    stdout: SharedStdout,
+    tools: Tools,
+    preludes: Vec<(String, String)>,
+    /// top-level `let`, `const` and `class` of the scripts, not on the global object
+    lexical: Vec<String>,
+}
+
+impl JsEngine {
+    pub fn new(tools: Tools) -> JsResult<Self> {
+        let stdout = SharedStdout::default();
+        let mut context = new_js(stdout.clone(), &tools)?;
+        let builtins = global_names(&mut context)?.into_iter().collect();
+        Ok(JsEngine {
+            context,
+            builtins,
+            stdout,
+            tools,
+            preludes: Vec::new(),
+            lexical: Vec::new(),
+        })
+    }
+
+    /// boa keeps no position for errors thrown while running, only syntax errors have a line
+    fn script_error(&mut self, code: &str, err: JsError) -> ScriptError {
+        let (kind, message, line, column) = match err.try_native(&mut self.context) {
+            Ok(native) => {
+                let message = native.message();
+                match &native.kind {
+                    JsNativeErrorKind::Syntax => {
+                        let (message, line, column) = split_position(message);
+                        (ErrorKind::Syntax, message.to_string(), line, column)
+                    }
+                    JsNativeErrorKind::Reference => {
+                        // `name is not defined` is an unknown function when it is called
+                        let called = message
+                            .strip_suffix(" is not defined")
+                            .is_some_and(|name| code.contains(&format!("{name}(")));
+                        let kind = if called {
+                            ErrorKind::UnknownFunction
+                        } else {
+                            ErrorKind::Runtime
+                        };
+                        (kind, native.to_string(), None, None)
+                    }
+                    JsNativeErrorKind::Error if error::is_tool_error(&self.tools, message) => {
+                        (ErrorKind::Tool, message.to_string(), None, None)
+                    }
+                    _ => (ErrorKind::Runtime, native.to_string(), None, None),
+                }
+            }
+            Err(_) => (ErrorKind::Runtime, err.to_string(), None, None),
+        };
+        let variables = super::ScriptEngin::variables(self);
+        ScriptError::new(kind, message)
+            .at(code, line, column)
+            .with_hint(&self.tools, &variables)
+    }
+}
+
+impl super::ScriptEngin for JsEngine {
+    fn language(&self) -> &'static str {
+        "js"
+    }
+
+    fn eval(&mut self, code: &str) -> Result<serde_json::Value, ScriptError> {
+        for name in lexical_names(code) {
+            if !self.lexical.contains(&name) {
+                self.lexical.push(name);
+            }
+        }
+        let r = self
+            .context
+            .eval(Source::from_bytes(code))
+            .and_then(|v| to_json(&v, &mut self.context));
+        r.map_err(|err| self.script_error(code, err))
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        args: Vec<serde_json::Value>,
+    ) -> Result<serde_json::Value, ScriptError> {
+        let r = (|| {
+            let context = &mut self.context;
+            let f = context.global_object().get(JsString::from(name), context)?;
+            let f = f.as_callable().ok_or_else(|| {
+                JsNativeError::reference().with_message(format!("{name} is not defined"))
+            })?;
+            let args = args
+                .iter()
+                .map(|arg| JsValue::from_json(arg, context))
+                .collect::<JsResult<Vec<_>>>()?;
+            let v = f.call(&JsValue::undefined(), &args, context)?;
+            to_json(&v, context)
+        })();
+        r.map_err(|err| self.script_error(&format!("{name}()"), err))
+    }
+
+    fn load_prelude(&mut self, name: &str, code: &str) -> Result<(), String> {
+        if let Err(err) = self.context.eval(Source::from_bytes(code)) {
+            // `name:line:column:` like lua, boa only knows where syntax errors are
+            let err = self.script_error(code, err);
+            let at = match (err.line, err.column) {
+                (Some(line), Some(column)) => format!("{name}:{line}:{column}"),
+                (Some(line), None) => format!("{name}:{line}"),
+                _ => name.to_string(),
+            };
+            return Err(format!("{at}: {}", err.message));
+        }
+        self.builtins = global_names(&mut self.context)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .collect();
+        self.preludes.push((name.to_string(), code.to_string()));
+        Ok(())
+    }
+
+    fn take_stdout(&mut self) -> Vec<String> {
+        self.stdout.borrow_mut().take()
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        let preludes = std::mem::take(&mut self.preludes);
+        *self = Self::new(self.tools.clone()).map_err(|e| e.to_string())?;
+        for (name, code) in preludes {
+            self.load_prelude(&name, &code)?;
+        }
+        self.stdout.borrow_mut().take();
+        Ok(())
+    }
+
+    fn variables(&mut self) -> Vec<(String, String)> {
+        let context = &mut self.context;
+        let mut vars = Vec::new();
+        let globals = global_names(context).unwrap_or_default();
+        for name in globals.iter().chain(&self.lexical) {
+            if self.builtins.contains(name) || vars.iter().any(|(n, _)| n == name) {
+                continue;
+            }
+            let value = if globals.contains(name) {
+                context
+                    .global_object()
+                    .get(JsString::from(name.as_str()), context)
+            } else {
+                // only reachable by evaluating it, which fails before its declaration ran
+                context.eval(Source::from_bytes(name.as_str()))
+            };
+            let value = match value {
+                Ok(v) if v.is_callable() => "function".to_string(),
+                Ok(v) => to_json(&v, context)
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|_| v.type_of().to_string()),
+                Err(_) => continue,
+            };
+            vars.push((name.clone(), value));
+        }
+        vars.sort();
+        vars
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tool_env::ScriptEngin;
+
+    #[test]
+    fn top_level_lexical_names() {
+        let code = r#"
+let a = 1, b = [1, 2], c;
+const d = { e: 1, f: "g, h" };
+class K {}
+function f(x, y) { let inner = 1; }
+for (let i = 0; i < 2; i++) {}
+if (a) { const block = 2; }
+// let comment = 1
+const { destructured } = d;
+let s = "let quoted = 1", t = `x`; var v = 1
+"#;
+        assert_eq!(lexical_names(code), ["a", "b", "c", "d", "K", "s", "t"]);
+    }
+
+    #[test]
+    fn variables_include_let_and_const() {
+        let mut engine = JsEngine::new(Vec::new()).unwrap();
+        engine
+            .eval("var v = 1; let l = [2]; const c = { x: 3 }; function f() {}")
+            .unwrap();
+        assert_eq!(
+            engine.variables(),
+            [
+                ("c".to_string(), r#"{"x":3}"#.to_string()),
+                ("f".to_string(), "function".to_string()),
+                ("l".to_string(), "[2]".to_string()),
+                ("v".to_string(), "1".to_string()),
+            ]
+        );
+
+        engine.reset().unwrap();
+        assert!(engine.variables().is_empty());
+    }
+
+    #[test]
+    fn prelude_errors_have_the_file_and_line() {
+        let mut engine = JsEngine::new(Vec::new()).unwrap();
+        let err = engine
+            .load_prelude("prelude.js", "let a = 1;\nlet = = 2;")
+            .unwrap_err();
+        assert!(err.starts_with("prelude.js:2:"), "{err}");
+
+        let err = engine
+            .load_prelude("runtime.js", "undefined_function()")
+            .unwrap_err();
+        assert!(err.starts_with("runtime.js: "), "{err}");
+    }
+}
@@ -1,66 +1,327 @@
+use std::collections::HashSet;
+
 use mlua::prelude::*;
 
-pub fn new_lua() -> Result<Lua, LuaError> {
-    let lua = Lua::new();
-
-    let send_sms = lua.create_function(
-        |lua, (number, sms_msg): (String, String)| -> LuaResult<mlua::Table> {
-            let r = lua.create_table()?;
-            r.set("status", "ok")?;
-            r.set("number", number)?;
-            r.set("sms_msg", sms_msg)?;
-            Ok(r)
-        },
-    )?;
-
-    let send_msg = lua.create_function(
-        |lua, (room_id, message): (u64, String)| -> LuaResult<mlua::Table> {
-            let r = lua.create_table()?;
-            r.set("status", "ok")?;
-            r.set("room_id", room_id)?;
-            r.set("message", message)?;
-            Ok(r)
-        },
-    )?;
-
-    let remember = lua.create_function(
-        |lua, (_time, _text): (u64, String)| -> LuaResult<mlua::Table> {
-            let r = lua.create_table()?;
-            r.set("status", "ok")?;
-            Ok(r)
-        },
-    )?;
-
-    let get_weather = lua.create_function(|lua, _: ()| -> LuaResult<mlua::Table> {
-        let r = lua.create_table()?;
-        r.set("status", "ok")?;
-        r.set("temp", "18")?;
-        r.set("weather", "雨")?;
-        Ok(r)
-    })?;
+use super::{
+    error::{self, ErrorKind, ScriptError},
+    SharedStdout, Tools,
+};
+
+/// Chunk name of evaluated scripts, lua reports positions as `script:LINE:`
+const CHUNK: &str = "script";
+
+fn bind_tools(lua: &Lua, tools: &Tools) -> LuaResult<()> {
+    for tool in tools {
+        let t = tool.clone();
+        let f = lua.create_function(move |lua, args: LuaMultiValue| {
+            let args = args
+                .into_iter()
+                .map(|arg| lua.from_value(arg))
+                .collect::<LuaResult<Vec<serde_json::Value>>>()?;
+            let r = t
+                .call(args)
+                .map_err(|e| LuaError::RuntimeError(error::tool_message(t.name(), &e)))?;
+            lua.to_value(&r)
+        })?;
+        lua.globals().set(tool.name(), f)?;
+    }
+    Ok(())
+}
+
+/// A Lua without files, processes, environment or module loading,
+/// tools are the only way out of the script.
+pub fn new_lua(stdout: SharedStdout, tools: &Tools) -> Result<Lua, LuaError> {
+    let libs = LuaStdLib::ALL_SAFE ^ (LuaStdLib::IO | LuaStdLib::OS | LuaStdLib::PACKAGE);
+    let lua = Lua::new_with(libs, LuaOptions::default())?;
+    for name in ["dofile", "loadfile", "require"] {
+        lua.globals().set(name, LuaNil)?;
+    }
 
-    let get_current_time = lua.create_function(|lua, _: ()| -> LuaResult<mlua::Table> {
-        let time = chrono::Local::now().to_rfc3339();
-        let r = lua.create_table()?;
-        r.set("status", "ok")?;
-        r.set("time", time)?;
-        Ok(r)
+    let print = lua.create_function(move |_, args: LuaMultiValue| {
+        let line = args
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<LuaResult<Vec<_>>>()?
+            .join("\t");
+        stdout.borrow_mut().push(line);
+        Ok(())
     })?;
 
-    lua.globals().set("send_sms", send_sms)?;
-    lua.globals().set("send_msg", send_msg)?;
-    lua.globals().set("remember", remember)?;
-    lua.globals().set("get_weather", get_weather)?;
-    lua.globals().set("get_current_time", get_current_time)?;
+    lua.globals().set("print", print)?;
+
+    bind_tools(&lua, tools)?;
 
     Ok(lua)
 }
 
-impl super::ScriptEngin for Lua {
-    fn eval(&self, code: &str) -> Result<String, String> {
-        self.load(code)
+/// Split `script:LINE: message` into the line and the message
+fn split_position(message: &str) -> (Option<usize>, &str) {
+    let position = message
+        .strip_prefix(CHUNK)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .and_then(|rest| rest.split_once(':'))
+        .and_then(|(line, rest)| Some((line.parse().ok()?, rest.trim_start())));
+    match position {
+        Some((line, message)) => (Some(line), message),
+        None => (None, message),
+    }
+}
+
+fn global_names(lua: &Lua) -> HashSet<String> {
+    lua.globals()
+        .pairs::<String, LuaValue>()
+        .filter_map(|kv| kv.ok().map(|(k, _)| k))
+        .collect()
+}
+
+pub struct LuaEngine {
+    lua: Lua,
+    builtins: HashSet<String>,
+    stdout: SharedStdout,
+    tools: Tools,
+    preludes: Vec<(String, String)>,
+}
+
+impl LuaEngine {
+    pub fn new(tools: Tools) -> Result<Self, LuaError> {
+        let stdout = SharedStdout::default();
+        let lua = new_lua(stdout.clone(), &tools)?;
+        let builtins = global_names(&lua);
+        Ok(LuaEngine {
+            lua,
+            builtins,
+            stdout,
+            tools,
+            preludes: Vec::new(),
+        })
+    }
+
+    fn script_error(&mut self, code: &str, err: LuaError) -> ScriptError {
+        let (kind, message, line) = match &err {
+            LuaError::SyntaxError { message, .. } => {
+                let (line, message) = split_position(message);
+                (ErrorKind::Syntax, message.to_string(), line)
+            }
+            LuaError::RuntimeError(message) => {
+                let (line, message) = split_position(message);
+                let kind = if message.starts_with("attempt to call a nil value (global") {
+                    ErrorKind::UnknownFunction
+                } else {
+                    ErrorKind::Runtime
+                };
+                (kind, message.to_string(), line)
+            }
+            // raised by a tool, the traceback has where it was called
+            LuaError::CallbackError { traceback, cause } => {
+                // without the `runtime error: ` the display adds
+                let message = match cause.as_ref() {
+                    LuaError::RuntimeError(message) => message.clone(),
+                    cause => cause.to_string(),
+                };
+                let kind = if error::is_tool_error(&self.tools, &message) {
+                    ErrorKind::Tool
+                } else {
+                    ErrorKind::Runtime
+                };
+                let line = traceback
+                    .lines()
+                    .find_map(|line| split_position(line.trim()).0);
+                (kind, message, line)
+            }
+            err => (ErrorKind::Runtime, err.to_string(), None),
+        };
+        let variables = self.variables();
+        ScriptError::new(kind, message)
+            .at(code, line, None)
+            .with_hint(&self.tools, &variables)
+    }
+}
+
+impl super::ScriptEngin for LuaEngine {
+    fn language(&self) -> &'static str {
+        "lua"
+    }
+
+    fn eval(&mut self, code: &str) -> Result<serde_json::Value, ScriptError> {
+        let r = self
+            .lua
+            .load(code)
+            .set_name(format!("={CHUNK}"))
             .eval::<mlua::Value>()
-            .map_err(|e| e.to_string())
-            .and_then(|v| serde_json::to_string(&v).map_err(|e| e.to_string()))
+            .and_then(|v| serde_json::to_value(&v).map_err(LuaError::external));
+        r.map_err(|err| self.script_error(code, err))
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        args: Vec<serde_json::Value>,
+    ) -> Result<serde_json::Value, ScriptError> {
+        let r = match self.lua.globals().get::<_, LuaFunction>(name) {
+            Ok(f) => args
+                .iter()
+                .map(|arg| self.lua.to_value(arg))
+                .collect::<LuaResult<Vec<_>>>()
+                .and_then(|args| f.call::<_, LuaValue>(LuaMultiValue::from_vec(args)))
+                .and_then(|v| serde_json::to_value(&v).map_err(LuaError::external)),
+            // the same error as calling it from a script
+            Err(_) => Err(LuaError::RuntimeError(format!(
+                "attempt to call a nil value (global '{name}')"
+            ))),
+        };
+        r.map_err(|err| self.script_error("", err))
+    }
+
+    fn load_prelude(&mut self, name: &str, code: &str) -> Result<(), String> {
+        // `@` makes lua report errors as `name:line:`
+        self.lua
+            .load(code)
+            .set_name(format!("@{name}"))
+            .exec()
+            .map_err(|e| e.to_string())?;
+        self.builtins = global_names(&self.lua);
+        self.preludes.push((name.to_string(), code.to_string()));
+        Ok(())
+    }
+
+    fn take_stdout(&mut self) -> Vec<String> {
+        self.stdout.borrow_mut().take()
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        let preludes = std::mem::take(&mut self.preludes);
+        *self = Self::new(self.tools.clone()).map_err(|e| e.to_string())?;
+        for (name, code) in preludes {
+            self.load_prelude(&name, &code)?;
+        }
+        self.stdout.borrow_mut().take();
+        Ok(())
+    }
+
+    fn variables(&mut self) -> Vec<(String, String)> {
+        let mut vars: Vec<(String, String)> = self
+            .lua
+            .globals()
+            .pairs::<String, LuaValue>()
+            .filter_map(|kv| kv.ok())
+            .filter(|(k, _)| !self.builtins.contains(k))
+            .map(|(k, v)| {
+                let v = match v {
+                    LuaValue::Function(_) => "function".to_string(),
+                    v => serde_json::to_string(&v).unwrap_or_else(|_| v.type_name().to_string()),
+                };
+                (k, v)
+            })
+            .collect();
+        vars.sort();
+        vars
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::tool_env::{ScriptEngin, Tool};
+
+    /// A tool that refuses every call
+    struct Fail;
+
+    impl Tool for Fail {
+        fn name(&self) -> &str {
+            "fail"
+        }
+
+        fn params(&self) -> Vec<String> {
+            vec!["x".to_string()]
+        }
+
+        fn call(&self, _: Vec<serde_json::Value>) -> anyhow::Result<serde_json::Value> {
+            anyhow::bail!("boom")
+        }
+    }
+
+    #[test]
+    fn no_files_processes_or_modules() {
+        let mut lua = LuaEngine::new(vec![]).unwrap();
+        for name in ["io", "os", "package", "require", "dofile", "loadfile"] {
+            let value = lua.eval(&format!("return {name} == nil")).unwrap();
+            assert_eq!(value, serde_json::json!(true), "{name}");
+        }
+        assert!(lua.eval("return string.upper('a')").is_ok());
+    }
+
+    fn engine() -> LuaEngine {
+        LuaEngine::new(vec![Arc::new(Fail) as Arc<dyn Tool>]).unwrap()
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_line() {
+        let err = engine().eval("local a = 1\nlocal b = = 2").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Syntax);
+        assert_eq!(err.line, Some(2));
+        // lua doesn't report the column
+        assert_eq!(err.column, None);
+        assert_eq!(err.source.as_deref(), Some("local b = = 2"));
+    }
+
+    #[test]
+    fn unknown_functions_list_the_available_ones() {
+        let mut lua = engine();
+        lua.eval("function mine(a) return a end").unwrap();
+        let err = lua.eval("local a = 1\nnope(a)").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnknownFunction);
+        assert_eq!(err.line, Some(2));
+        let hint = err.hint.unwrap();
+        assert!(hint.starts_with("available functions: "), "{hint}");
+        assert!(
+            hint.contains("fail(x)") && hint.contains("mine(...)"),
+            "{hint}"
+        );
+    }
+
+    #[test]
+    fn failed_tools_have_their_usage() {
+        let err = engine().eval("\nreturn fail(1)").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Tool);
+        assert_eq!(err.message, "fail: boom");
+        assert_eq!(err.line, Some(2));
+        assert_eq!(err.hint.as_deref(), Some("usage: fail(x)"));
+    }
+
+    #[test]
+    fn definitions_last_until_reset() {
+        let mut lua = engine();
+        lua.eval("x = 41\nfunction inc(a) return a + 1 end")
+            .unwrap();
+        assert_eq!(lua.eval("return inc(x)").unwrap(), serde_json::json!(42));
+
+        lua.load_prelude("p", "greeting = 'hi'\nfunction helper() return 1 end")
+            .unwrap();
+        lua.reset().unwrap();
+        assert_eq!(lua.eval("return x").unwrap(), serde_json::Value::Null);
+        assert!(lua.eval("return inc(1)").is_err());
+        assert_eq!(lua.eval("return helper()").unwrap(), serde_json::json!(1));
+        assert_eq!(
+            lua.eval("return greeting").unwrap(),
+            serde_json::json!("hi")
+        );
+    }
+
+    #[test]
+    fn variables_leave_out_builtins_and_preludes() {
+        let mut lua = engine();
+        lua.load_prelude("p", "greeting = 'hi'\nfunction helper() return 1 end")
+            .unwrap();
+        assert!(lua.variables().is_empty());
+        lua.eval("x = 1\nfunction f() end").unwrap();
+        assert_eq!(
+            lua.variables(),
+            [
+                ("f".to_string(), "function".to_string()),
+                ("x".to_string(), "1".to_string()),
+            ]
+        );
     }
 }
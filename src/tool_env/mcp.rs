@@ -0,0 +1,408 @@
+//! Tools served by Model Context Protocol servers, spoken over stdio.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde_json::Value;
+
+use super::{Tool, Tools};
+
+pub(super) const PROTOCOL_VERSION: &str = "2024-11-05";
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct McpServerConfig {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// tools without side effects, every other tool needs the user's approval
+    #[serde(default)]
+    pub pure: Vec<String>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+struct Connection {
+    child: Child,
+    stdin: ChildStdin,
+    lines: crossbeam::channel::Receiver<String>,
+    next_id: u64,
+    timeout: Duration,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Connection {
+    fn send(&mut self, message: &Value) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        self.stdin.write_all(&line)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> anyhow::Result<()> {
+        self.send(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params
+        }))
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> anyhow::Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params
+        }))?;
+
+        loop {
+            let line = self
+                .lines
+                .recv_timeout(self.timeout)
+                .map_err(|_| anyhow::anyhow!("no response to `{method}`"))?;
+            let Ok(mut response) = serde_json::from_str::<Value>(&line) else {
+                log::debug!("mcp: skip non JSON line {line:?}");
+                continue;
+            };
+            // notifications and requests from the server are not supported
+            if response.get("id").and_then(Value::as_u64) != Some(id) {
+                continue;
+            }
+            if let Some(error) = response.get("error") {
+                let message = error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown error");
+                anyhow::bail!("`{method}` failed: {message}");
+            }
+            return Ok(response["result"].take());
+        }
+    }
+}
+
+pub struct McpTool {
+    name: String,
+    remote_name: String,
+    description: String,
+    params: Vec<String>,
+    pure: bool,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl McpTool {
+    /// Scripts call tools with positional arguments, MCP wants an object.
+    /// A single object whose keys are all parameter names is passed as is.
+    fn arguments(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+        if let [Value::Object(obj)] = args.as_slice() {
+            if obj.keys().all(|k| self.params.contains(k)) {
+                return Ok(args.into_iter().next().unwrap_or_default());
+            }
+        }
+        if args.len() > self.params.len() {
+            anyhow::bail!(
+                "`{}` takes {} arguments ({}), got {}",
+                self.name,
+                self.params.len(),
+                self.params.join(", "),
+                args.len()
+            );
+        }
+        Ok(Value::Object(
+            self.params.iter().cloned().zip(args).collect(),
+        ))
+    }
+}
+
+impl Tool for McpTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn params(&self) -> Vec<String> {
+        self.params.clone()
+    }
+
+    fn is_pure(&self) -> bool {
+        self.pure
+    }
+
+    fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+        let arguments = self.arguments(args)?;
+        let mut result = self.connection.lock().unwrap().request(
+            "tools/call",
+            serde_json::json!({
+                "name": self.remote_name,
+                "arguments": arguments
+            }),
+        )?;
+
+        let text: Vec<Value> = result["content"]
+            .as_array()
+            .map(|content| {
+                content
+                    .iter()
+                    .filter_map(|c| c.get("text").and_then(Value::as_str))
+                    .map(|text| serde_json::from_str(text).unwrap_or_else(|_| text.into()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if result["isError"].as_bool().unwrap_or(false) {
+            let message: Vec<String> = text
+                .iter()
+                .map(|t| match t {
+                    Value::String(s) => s.clone(),
+                    t => t.to_string(),
+                })
+                .collect();
+            anyhow::bail!("{}", message.join("\n"));
+        }
+        if let Some(structured) = result.get_mut("structuredContent") {
+            return Ok(structured.take());
+        }
+        match <[Value; 1]>::try_from(text) {
+            Ok([value]) => Ok(value),
+            Err(text) => Ok(text.into()),
+        }
+    }
+}
+
+/// Parameter names in calling order: required ones first, as the schema lists them.
+fn param_names(schema: &Value) -> Vec<String> {
+    let mut params: Vec<String> = schema["required"]
+        .as_array()
+        .map(|r| {
+            r.iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Some(properties) = schema["properties"].as_object() {
+        for name in properties.keys() {
+            if !params.contains(name) {
+                params.push(name.clone());
+            }
+        }
+    }
+    params
+}
+
+fn connect(config: &McpServerConfig) -> anyhow::Result<Tools> {
+    let mut child = Command::new(&config.command)
+        .args(&config.args)
+        .envs(&config.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        // the terminal belongs to the TUI
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let stdin = child.stdin.take().context("no stdin")?;
+    let stdout = child.stdout.take().context("no stdout")?;
+    let (lines_tx, lines) = crossbeam::channel::unbounded();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else { break };
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut connection = Connection {
+        child,
+        stdin,
+        lines,
+        next_id: 0,
+        timeout: Duration::from_secs(config.timeout_secs),
+    };
+
+    connection.request(
+        "initialize",
+        serde_json::json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION")
+            }
+        }),
+    )?;
+    connection.notify("notifications/initialized", serde_json::json!({}))?;
+
+    let mut remote_tools = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let params = match &cursor {
+            Some(cursor) => serde_json::json!({ "cursor": cursor }),
+            None => serde_json::json!({}),
+        };
+        let mut page = connection.request("tools/list", params)?;
+        if let Some(tools) = page["tools"].as_array_mut() {
+            remote_tools.append(tools);
+        }
+        match page["nextCursor"].as_str() {
+            Some(next) => cursor = Some(next.to_string()),
+            None => break,
+        }
+    }
+
+    let connection = Arc::new(Mutex::new(connection));
+    let tools = remote_tools
+        .into_iter()
+        .filter_map(|tool| {
+            let remote_name = tool["name"].as_str()?.to_string();
+            // script identifiers can't contain `-` or `.`
+            let name = remote_name
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect();
+            Some(Arc::new(McpTool {
+                name,
+                description: tool["description"].as_str().unwrap_or_default().to_string(),
+                params: param_names(&tool["inputSchema"]),
+                pure: config.pure.contains(&remote_name),
+                remote_name,
+                connection: connection.clone(),
+            }) as Arc<dyn Tool>)
+        })
+        .collect();
+    Ok(tools)
+}
+
+pub fn connect_all(configs: &[McpServerConfig]) -> anyhow::Result<Tools> {
+    let mut tools = Vec::new();
+    for config in configs {
+        let server_tools =
+            connect(config).with_context(|| format!("mcp server `{}` failed", config.name))?;
+        log::info!("mcp server `{}`: {} tools", config.name, server_tools.len());
+        tools.extend(server_tools);
+    }
+    Ok(tools)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Answers by the method and the tool name in the request line, echoing the arguments
+    /// of `echo-args` back as text
+    const STUB: &str = r#"
+echo "starting"
+while IFS= read -r line; do
+  id=$(printf '%s' "$line" | sed -n 's/.*"id":\([0-9]*\).*/\1/p')
+  case "$line" in
+    *'"method":"initialize"'*)
+      echo '{"jsonrpc":"2.0","method":"notifications/message","params":{}}'
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"protocolVersion\":\"2024-11-05\",\"capabilities\":{\"tools\":{}},\"serverInfo\":{\"name\":\"stub\",\"version\":\"0\"}}}" ;;
+    *'"method":"tools/list"'*)
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"tools\":[{\"name\":\"echo-args\",\"description\":\"echo\",\"inputSchema\":{\"type\":\"object\",\"properties\":{\"b\":{},\"a\":{}},\"required\":[\"a\"]}},{\"name\":\"refuse\",\"inputSchema\":{}},{\"name\":\"crash\",\"inputSchema\":{}}]}}" ;;
+    *'"name":"echo-args"'*)
+      args=$(printf '%s' "$line" | sed -n 's/.*"arguments":\({[^}]*}\).*/\1/p' | sed 's/"/\\"/g')
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"content\":[{\"type\":\"text\",\"text\":\"$args\"}]}}" ;;
+    *'"name":"refuse"'*)
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"isError\":true,\"content\":[{\"type\":\"text\",\"text\":\"not today\"}]}}" ;;
+    *'"name":"crash"'*)
+      echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"error\":{\"code\":-32603,\"message\":\"stub crashed\"}}" ;;
+  esac
+done
+"#;
+
+    fn stub(pure: &[&str]) -> Tools {
+        connect(&McpServerConfig {
+            name: "stub".to_string(),
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), STUB.to_string()],
+            env: HashMap::new(),
+            pure: pure.iter().map(|name| name.to_string()).collect(),
+            timeout_secs: 5,
+        })
+        .unwrap()
+    }
+
+    fn tool<'a>(tools: &'a Tools, name: &str) -> &'a Arc<dyn Tool> {
+        tools.iter().find(|tool| tool.name() == name).unwrap()
+    }
+
+    #[test]
+    fn lists_the_tools_after_initialize() {
+        let tools = stub(&["echo-args"]);
+        let names: Vec<&str> = tools.iter().map(|tool| tool.name()).collect();
+        assert_eq!(names, ["echo_args", "refuse", "crash"]);
+
+        let echo = tool(&tools, "echo_args");
+        assert_eq!(echo.description(), "echo");
+        assert_eq!(echo.params(), ["a", "b"]);
+        assert!(echo.is_pure());
+        assert!(!tool(&tools, "refuse").is_pure());
+    }
+
+    #[test]
+    fn calls_with_named_arguments() {
+        let tools = stub(&[]);
+        let echo = tool(&tools, "echo_args");
+        assert_eq!(
+            echo.call(vec![1.into(), "x".into()]).unwrap(),
+            json!({ "a": 1, "b": "x" })
+        );
+        assert_eq!(
+            echo.call(vec![json!({ "b": 2, "a": 1 })]).unwrap(),
+            json!({ "b": 2, "a": 1 })
+        );
+        let err = echo.call(vec![1.into(), 2.into(), 3.into()]).unwrap_err();
+        assert!(err.to_string().contains("takes 2 arguments"), "{err}");
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let tools = stub(&[]);
+        let err = tool(&tools, "refuse").call(vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "not today");
+
+        let err = tool(&tools, "crash").call(vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "`tools/call` failed: stub crashed");
+    }
+
+    #[test]
+    fn a_server_that_never_answers_times_out() {
+        let err = connect(&McpServerConfig {
+            name: "mute".to_string(),
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), "cat > /dev/null".to_string()],
+            env: HashMap::new(),
+            pure: Vec::new(),
+            timeout_secs: 1,
+        })
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "no response to `initialize`");
+    }
+}
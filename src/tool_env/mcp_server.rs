@@ -0,0 +1,355 @@
+//! Serve the tools, and optionally a script engine, as a Model Context Protocol server over stdio.
+
+use std::{
+    io::{BufRead, Write},
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use super::{
+    audit::AuditLog, error::ScriptError, mcp::PROTOCOL_VERSION, positional_args, tool_result,
+    ScriptEngin, Tools,
+};
+
+const EVAL_SCRIPT: &str = "eval_script";
+
+pub struct McpServer {
+    engine: Option<Box<dyn ScriptEngin>>,
+    tools: Tools,
+    /// the scripts and the calls of the client, nobody watches them otherwise
+    audit: Option<AuditLog>,
+}
+
+fn text_result(value: Value, is_error: bool) -> Value {
+    let text = match value {
+        Value::String(s) => s,
+        value => value.to_string(),
+    };
+    serde_json::json!({
+        "content": [{ "type": "text", "text": text }],
+        "isError": is_error
+    })
+}
+
+impl McpServer {
+    pub fn new(
+        engine: Option<Box<dyn ScriptEngin>>,
+        tools: Tools,
+        audit: Option<AuditLog>,
+    ) -> Self {
+        McpServer {
+            engine,
+            tools,
+            audit,
+        }
+    }
+
+    fn record(&mut self, code: &str, duration: Duration, result: &Result<Value, ScriptError>) {
+        let Some(audit) = &mut self.audit else {
+            return;
+        };
+        let engine = self
+            .engine
+            .as_ref()
+            .map_or("none", |engine| engine.language());
+        // the client is one conversation
+        if let Err(err) = audit.write(engine, 0, code, duration, result) {
+            log::error!("audit log error: {err:#}");
+        }
+    }
+
+    fn list_tools(&self) -> Value {
+        let mut list: Vec<Value> = self
+            .tools
+            .iter()
+            .map(|tool| {
+                let params = tool.params();
+                let properties: serde_json::Map<String, Value> = params
+                    .iter()
+                    .map(|p| (p.clone(), serde_json::json!({})))
+                    .collect();
+                let input_schema = serde_json::json!({
+                    "type": "object",
+                    "properties": properties,
+                    "required": params
+                });
+                serde_json::json!({
+                    "name": tool.name(),
+                    "description": tool.description(),
+                    "inputSchema": input_schema
+                })
+            })
+            .collect();
+
+        if let Some(engine) = &self.engine {
+            list.push(serde_json::json!({
+                "name": EVAL_SCRIPT,
+                "description": format!(
+                    "Evaluate a {} script and return its value. The other tools are global functions, and variables stay defined between calls.",
+                    engine.language()
+                ),
+                "inputSchema": {
+                    "type": "object",
+                    "properties": { "code": { "type": "string" } },
+                    "required": ["code"]
+                }
+            }));
+        }
+        serde_json::json!({ "tools": list })
+    }
+
+    fn call_tool(&mut self, name: &str, arguments: Value) -> Result<Value, (i64, String)> {
+        if name == EVAL_SCRIPT {
+            if let Some(engine) = &mut self.engine {
+                let Some(code) = arguments["code"].as_str() else {
+                    return Err((-32602, "`code` must be a string".to_string()));
+                };
+                let code = code.to_string();
+                let start = Instant::now();
+                let result = engine.eval(&code);
+                let elapsed = start.elapsed();
+                self.record(&code, elapsed, &result);
+                let engine = self.engine.as_mut().unwrap();
+                let is_error = result.is_err();
+                return Ok(text_result(tool_result(engine.as_mut(), result), is_error));
+            }
+        }
+
+        let Some(tool) = self.tools.iter().find(|tool| tool.name() == name) else {
+            return Err((-32602, format!("unknown tool `{name}`")));
+        };
+        let args = match arguments {
+            Value::Null => Vec::new(),
+            // a tool that doesn't name its parameters, like a plugin's, may still get `args`
+            Value::Object(mut named) if tool.params().is_empty() && named.contains_key("args") => {
+                match named.remove("args") {
+                    Some(Value::Array(args)) if named.is_empty() => args,
+                    _ => return Err((-32602, "`args` must be an array".to_string())),
+                }
+            }
+            Value::Object(named) => positional_args(tool.as_ref(), named)
+                .map_err(|e| (-32602, format!("`{name}`: {e:#}")))?,
+            _ => return Err((-32602, "`arguments` must be an object".to_string())),
+        };
+
+        let code = format!(
+            "{name}({})",
+            args.iter()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let start = Instant::now();
+        let result = tool
+            .call(args)
+            .map_err(|e| ScriptError::from(format!("{e:#}")));
+        self.record(&code, start.elapsed(), &result);
+        Ok(match result {
+            Ok(value) => text_result(value, false),
+            Err(e) => text_result(e.message.into(), true),
+        })
+    }
+
+    fn handle(&mut self, method: &str, mut params: Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(serde_json::json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {} },
+                "serverInfo": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION")
+                }
+            })),
+            "ping" => Ok(serde_json::json!({})),
+            "tools/list" => Ok(self.list_tools()),
+            "tools/call" => {
+                let Some(name) = params["name"].as_str().map(str::to_string) else {
+                    return Err((-32602, "missing tool name".to_string()));
+                };
+                self.call_tool(&name, params["arguments"].take())
+            }
+            method => Err((-32601, format!("method `{method}` not found"))),
+        }
+    }
+
+    /// Answer newline-delimited JSON-RPC requests on stdin until it is closed.
+    pub fn run_loop(mut self) -> anyhow::Result<()> {
+        let stdin = std::io::stdin();
+        let mut stdout = std::io::stdout();
+
+        for line in stdin.lock().lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut request: Value = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(e) => {
+                    let response = serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": null,
+                        "error": { "code": -32700, "message": e.to_string() }
+                    });
+                    writeln!(stdout, "{response}")?;
+                    stdout.flush()?;
+                    continue;
+                }
+            };
+
+            let method = request["method"].as_str().unwrap_or_default().to_string();
+            let Some(id) = request.get_mut("id").map(Value::take) else {
+                log::debug!("mcp server: notification `{method}`");
+                continue;
+            };
+
+            let response = match self.handle(&method, request["params"].take()) {
+                Ok(result) => serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": result
+                }),
+                Err((code, message)) => serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": code, "message": message }
+                }),
+            };
+            writeln!(stdout, "{response}")?;
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::tool_env::{rhai::RhaiEngine, Tool};
+
+    /// Answers with the arguments it got
+    struct Echo(&'static str, &'static [&'static str]);
+
+    impl Tool for Echo {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn params(&self) -> Vec<String> {
+            self.1.iter().map(|p| p.to_string()).collect()
+        }
+
+        fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+            Ok(args.into())
+        }
+    }
+
+    fn new_server(engine: bool) -> McpServer {
+        let tools: Tools = vec![
+            Arc::new(Echo("send_msg", &["to", "text", "silent"])),
+            Arc::new(Echo("now", &[])),
+        ];
+        let engine =
+            engine.then(|| Box::new(RhaiEngine::new(tools.clone())) as Box<dyn ScriptEngin>);
+        McpServer::new(engine, tools, None)
+    }
+
+    fn call(server: &mut McpServer, name: &str, arguments: Value) -> Result<Value, (i64, String)> {
+        server.handle(
+            "tools/call",
+            json!({ "name": name, "arguments": arguments }),
+        )
+    }
+
+    fn text(result: &Value) -> Value {
+        serde_json::from_str(result["content"][0]["text"].as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn initialize_and_ping() {
+        let mut server = new_server(false);
+        let result = server.handle("initialize", json!({})).unwrap();
+        assert_eq!(result["protocolVersion"], PROTOCOL_VERSION);
+        assert_eq!(result["capabilities"], json!({ "tools": {} }));
+        assert_eq!(server.handle("ping", Value::Null).unwrap(), json!({}));
+        assert_eq!(server.handle("nope", Value::Null).unwrap_err().0, -32601);
+    }
+
+    #[test]
+    fn tools_are_listed_with_their_parameters() {
+        let list = new_server(true).handle("tools/list", Value::Null).unwrap();
+        let tools = list["tools"].as_array().unwrap();
+        assert_eq!(tools.len(), 3);
+        assert_eq!(
+            tools[0]["inputSchema"],
+            json!({
+                "type": "object",
+                "properties": { "to": {}, "text": {}, "silent": {} },
+                "required": ["to", "text", "silent"]
+            })
+        );
+        assert_eq!(
+            tools[1]["inputSchema"],
+            json!({ "type": "object", "properties": {}, "required": [] })
+        );
+        assert_eq!(tools[2]["name"], EVAL_SCRIPT);
+
+        let list = new_server(false).handle("tools/list", Value::Null).unwrap();
+        assert_eq!(list["tools"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn named_arguments_are_passed_in_order() {
+        let mut server = new_server(false);
+        let result = call(&mut server, "send_msg", json!({ "text": "hi", "to": 1 })).unwrap();
+        assert_eq!(result["isError"], false);
+        assert_eq!(text(&result), json!([1, "hi"]));
+
+        // an explicit null is kept, a missing argument before a given one is null
+        let result = call(&mut server, "send_msg", json!({ "to": 1, "silent": null })).unwrap();
+        assert_eq!(text(&result), json!([1, null, null]));
+        let result = call(&mut server, "send_msg", json!({ "silent": true })).unwrap();
+        assert_eq!(text(&result), json!([null, null, true]));
+
+        let result = call(&mut server, "now", Value::Null).unwrap();
+        assert_eq!(text(&result), json!([]));
+        let result = call(&mut server, "now", json!({ "args": [1] })).unwrap();
+        assert_eq!(text(&result), json!([1]));
+
+        let (code, message) = call(&mut server, "send_msg", json!({ "body": "hi" })).unwrap_err();
+        assert_eq!(code, -32602);
+        assert!(message.contains("`body`"), "{message}");
+        assert_eq!(call(&mut server, "nope", json!({})).unwrap_err().0, -32602);
+    }
+
+    #[test]
+    fn scripts_call_the_tools() {
+        let mut server = new_server(true);
+        let result = call(
+            &mut server,
+            EVAL_SCRIPT,
+            json!({ "code": "send_msg(1, \"hi\")" }),
+        )
+        .unwrap();
+        assert_eq!(result["isError"], false);
+        assert_eq!(text(&result), json!({ "value": [1, "hi"], "stdout": [] }));
+
+        let result = call(
+            &mut server,
+            EVAL_SCRIPT,
+            json!({ "code": "undefined_function()" }),
+        )
+        .unwrap();
+        assert_eq!(result["isError"], true);
+        assert_eq!(text(&result)["status"], "error");
+
+        let (code, _) = call(&mut server, EVAL_SCRIPT, json!({ "code": 1 })).unwrap_err();
+        assert_eq!(code, -32602);
+        // without an engine there is no such tool
+        assert!(call(&mut new_server(false), EVAL_SCRIPT, json!({ "code": "1" })).is_err());
+    }
+}
@@ -1,65 +1,534 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
 use crate::{
-    chat::im_channel::{self, Message, MessageRx, MessageTx, Role},
+    chat::im_channel::{self, ChatId, Control, Message, MessageRx, MessageTx, Role},
     llm::local_llm::Token,
 };
+use approval::Approver;
+use audit::AuditLog;
+use error::ScriptError;
+use output::OutputLimiter;
+use protocol::{Action, Protocol};
 
+pub mod approval;
+pub mod audit;
+pub mod builtin;
+pub mod config_tool;
+pub mod error;
+pub mod fs;
+pub mod js;
 pub mod lua;
+pub mod mcp;
+pub mod mcp_server;
+pub mod output;
+pub mod protocol;
 pub mod rhai;
+pub mod sqlite;
+pub mod wasm;
+
+const MAX_STDOUT_LINES: usize = 50;
+const MAX_STDOUT_BYTES: usize = 4 * 1024;
+
+/// Collects what a script prints, so it ends up in the tool result
+/// instead of on the terminal.
+#[derive(Debug, Default)]
+pub struct Stdout {
+    lines: Vec<String>,
+    bytes: usize,
+    truncated: bool,
+}
+
+pub type SharedStdout = Rc<RefCell<Stdout>>;
+
+impl Stdout {
+    pub fn push(&mut self, line: String) {
+        if self.truncated {
+            return;
+        }
+        if self.lines.len() >= MAX_STDOUT_LINES || self.bytes + line.len() > MAX_STDOUT_BYTES {
+            self.truncated = true;
+            return;
+        }
+        self.bytes += line.len();
+        self.lines.push(line);
+    }
+
+    pub fn take(&mut self) -> Vec<String> {
+        let mut lines = std::mem::take(&mut self.lines);
+        if self.truncated {
+            lines.push("...(output truncated)".to_string());
+        }
+        self.bytes = 0;
+        self.truncated = false;
+        lines
+    }
+}
+
+/// A function bound into every script engine under [`Tool::name`],
+/// on top of the built-in ones.
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// One line telling a model what the tool does
+    fn description(&self) -> &str {
+        ""
+    }
+
+    /// Names of the positional arguments, empty when unknown
+    fn params(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// A pure tool has no side effects and runs without asking the user.
+    fn is_pure(&self) -> bool {
+        false
+    }
+
+    fn call(&self, args: Vec<serde_json::Value>) -> anyhow::Result<serde_json::Value>;
+}
+
+pub type Tools = Vec<Arc<dyn Tool>>;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    pub args: Vec<serde_json::Value>,
+}
 
 pub trait ScriptEngin {
-    fn eval(&self, code: &str) -> Result<String, String>;
+    /// Name of the script language, as used to tag fenced code blocks
+    fn language(&self) -> &'static str;
+
+    /// Evaluate `code`. Variables and functions it defines stay available to
+    /// the next call until [`ScriptEngin::reset`].
+    fn eval(&mut self, code: &str) -> Result<serde_json::Value, ScriptError>;
+
+    /// Call the global function `name`, for replies that are function-call objects
+    fn call(
+        &mut self,
+        name: &str,
+        args: Vec<serde_json::Value>,
+    ) -> Result<serde_json::Value, ScriptError>;
+
+    /// Evaluate the project prelude `name`. What it defines is left out of
+    /// [`ScriptEngin::variables`] and defined again by [`ScriptEngin::reset`].
+    fn load_prelude(&mut self, name: &str, code: &str) -> Result<(), String>;
+
+    /// Lines printed since the last call
+    fn take_stdout(&mut self) -> Vec<String>;
+
+    fn reset(&mut self) -> Result<(), String>;
+
+    /// `(name, value)` of everything the scripts have defined so far
+    fn variables(&mut self) -> Vec<(String, String)>;
+}
+
+impl<E: ScriptEngin + ?Sized> ScriptEngin for Box<E> {
+    fn language(&self) -> &'static str {
+        (**self).language()
+    }
+
+    fn eval(&mut self, code: &str) -> Result<serde_json::Value, ScriptError> {
+        (**self).eval(code)
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        args: Vec<serde_json::Value>,
+    ) -> Result<serde_json::Value, ScriptError> {
+        (**self).call(name, args)
+    }
+
+    fn load_prelude(&mut self, name: &str, code: &str) -> Result<(), String> {
+        (**self).load_prelude(name, code)
+    }
+
+    fn take_stdout(&mut self) -> Vec<String> {
+        (**self).take_stdout()
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        (**self).reset()
+    }
+
+    fn variables(&mut self) -> Vec<(String, String)> {
+        (**self).variables()
+    }
 }
 
+/// Named `args` in the order of the tool's parameters. A parameter left out is
+/// null, or dropped when no later one is given. A name the tool doesn't have is refused.
+pub fn positional_args(
+    tool: &dyn Tool,
+    mut args: serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<Vec<serde_json::Value>> {
+    let params = tool.params();
+    if let Some(unknown) = args.keys().find(|key| !params.contains(key)) {
+        anyhow::bail!("no parameter `{unknown}`");
+    }
+    let given = params
+        .iter()
+        .rposition(|param| args.contains_key(param))
+        .map_or(0, |i| i + 1);
+    Ok(params[..given]
+        .iter()
+        .map(|param| args.remove(param).unwrap_or_default())
+        .collect())
+}
+
+/// Evaluate `(name, code)` preludes in order, failing on the first error.
+pub fn load_preludes(
+    engine: &mut dyn ScriptEngin,
+    preludes: &[(String, String)],
+) -> anyhow::Result<()> {
+    for (name, code) in preludes {
+        engine
+            .load_prelude(name, code)
+            .map_err(|e| anyhow::anyhow!("prelude error: {e}"))?;
+    }
+    for line in engine.take_stdout() {
+        log::info!("prelude: {line}");
+    }
+    Ok(())
+}
+
+/// Build the tool result, `{"value": ..., "stdout": [...]}` or the error with its
+/// `stdout`, the same shape whether the script printed or not.
+pub fn tool_result(
+    engine: &mut dyn ScriptEngin,
+    result: Result<serde_json::Value, ScriptError>,
+) -> serde_json::Value {
+    let mut result = match result {
+        Ok(value) => serde_json::json!({ "value": value }),
+        Err(err) => err.to_json(),
+    };
+    result["stdout"] = engine.take_stdout().into();
+    result
+}
+
+/// Runs the scripts of every conversation, each in its own engine.
 pub struct ScriptExecutor<E: ScriptEngin> {
-    engine: E,
+    engines: HashMap<ChatId, E>,
+    /// builds the engine of a language, with the tools and the preludes
+    new_engine: Box<dyn FnMut(&str) -> anyhow::Result<E>>,
+    /// built up front to report prelude errors at startup, given to the first conversation
+    spare: Option<E>,
+    language: &'static str,
+    /// the conversations switched to another engine
+    languages: HashMap<ChatId, &'static str>,
+    /// the reply the model was writing may still come for them, it is not run
+    deleted: HashSet<ChatId>,
+    /// to match named arguments with the parameters
+    tools: Tools,
+    protocol: Protocol,
+    approver: Arc<Approver>,
+    limiter: OutputLimiter,
+    audit: Option<AuditLog>,
     rx: MessageRx,
     tx: MessageTx,
 }
 
 impl<E: ScriptEngin> ScriptExecutor<E> {
-    pub fn new(engine: E, rx: MessageRx, tx: MessageTx) -> Self {
-        ScriptExecutor { engine, rx, tx }
-    }
-
-    pub fn eval(&self, code: &str) -> Result<String, String> {
-        self.engine.eval(code)
-    }
-
-    pub fn run_loop(self) {
-        while let Ok(input) = self.rx.recv() {
-            if let Message {
-                role: Role::Assistant,
-                contont: Token::End(code),
-            } = input
-            {
-                match self.eval(&code) {
-                    Ok(result) => {
-                        let message = Message {
-                            role: Role::Tool,
-                            contont: Token::End(result),
-                        };
-                        if let Err(_) = self.tx.send(message) {
-                            break;
+    pub fn new(
+        language: &str,
+        mut new_engine: Box<dyn FnMut(&str) -> anyhow::Result<E>>,
+        tools: Tools,
+        protocol: Protocol,
+        approver: Arc<Approver>,
+        limiter: OutputLimiter,
+        audit: Option<AuditLog>,
+        rx: MessageRx,
+        tx: MessageTx,
+    ) -> anyhow::Result<Self> {
+        let spare = new_engine(language)?;
+        Ok(ScriptExecutor {
+            engines: HashMap::new(),
+            new_engine,
+            language: spare.language(),
+            languages: HashMap::new(),
+            deleted: HashSet::new(),
+            spare: Some(spare),
+            tools,
+            protocol,
+            approver,
+            limiter,
+            audit,
+            rx,
+            tx,
+        })
+    }
+
+    /// The engine of `chat_id`, out of the map while it runs
+    fn take_engine(&mut self, chat_id: ChatId) -> anyhow::Result<E> {
+        if let Some(engine) = self.engines.remove(&chat_id) {
+            return Ok(engine);
+        }
+        match self.languages.get(&chat_id) {
+            Some(language) => (self.new_engine)(language),
+            None => match self.spare.take() {
+                Some(engine) => Ok(engine),
+                None => (self.new_engine)(self.language),
+            },
+        }
+    }
+
+    fn language(&self, chat_id: ChatId) -> &'static str {
+        self.languages
+            .get(&chat_id)
+            .copied()
+            .unwrap_or(self.language)
+    }
+
+    /// What the UI is told, the conversation keeps its engine when the new one fails
+    fn set_engine(&mut self, chat_id: ChatId, language: &str) -> Control {
+        match (self.new_engine)(language) {
+            Ok(engine) => {
+                let language = engine.language();
+                self.languages.insert(chat_id, language);
+                self.engines.insert(chat_id, engine);
+                Control::SetEngine(language.to_string())
+            }
+            Err(err) => {
+                Control::EngineError(format!("switch to the {language} engine error: {err:#}"))
+            }
+        }
+    }
+
+    /// Run the scripts of the conversations that didn't pick an engine in `language`,
+    /// their engines start over. The conversations reset are returned.
+    fn set_default_engine(&mut self, language: &str) -> anyhow::Result<Vec<ChatId>> {
+        let engine = (self.new_engine)(language)?;
+        self.language = engine.language();
+        self.spare = Some(engine);
+        let reset = self
+            .engines
+            .keys()
+            .filter(|chat_id| !self.languages.contains_key(chat_id))
+            .copied()
+            .collect::<Vec<_>>();
+        for chat_id in &reset {
+            self.engines.remove(chat_id);
+        }
+        Ok(reset)
+    }
+
+    fn run(
+        &mut self,
+        chat_id: ChatId,
+        code: &str,
+        f: impl FnOnce(&mut E) -> Result<serde_json::Value, ScriptError>,
+    ) -> (serde_json::Value, Duration) {
+        self.approver.set_code(chat_id, code);
+        self.limiter.start_result();
+        let mut engine = match self.take_engine(chat_id) {
+            Ok(engine) => engine,
+            Err(err) => {
+                let err = ScriptError::from(format!("{err:#}")).to_json();
+                return (err, Duration::ZERO);
+            }
+        };
+        let start = Instant::now();
+        let result = f(&mut engine);
+        let elapsed = start.elapsed();
+        if let Some(audit) = &mut self.audit {
+            let language = self.language(chat_id);
+            if let Err(err) = audit.write(language, chat_id, code, elapsed, &result) {
+                log::error!("audit log error: {err:#}");
+            }
+        }
+        let result = tool_result(&mut engine, result);
+        self.engines.insert(chat_id, engine);
+        (result, elapsed)
+    }
+
+    /// The result of `code` and how long it ran
+    pub fn eval(&mut self, chat_id: ChatId, code: &str) -> (serde_json::Value, Duration) {
+        self.run(chat_id, code, |engine| engine.eval(code))
+    }
+
+    /// Call `name` with an array of arguments, or an object of the tool's named parameters
+    pub fn call(
+        &mut self,
+        chat_id: ChatId,
+        name: &str,
+        arguments: serde_json::Value,
+    ) -> (serde_json::Value, Duration) {
+        let written = format!("{name}({arguments})");
+        let args = match arguments {
+            serde_json::Value::Array(args) => Ok(args),
+            serde_json::Value::Object(named) => {
+                match self.tools.iter().find(|tool| tool.name() == name) {
+                    Some(tool) => positional_args(tool.as_ref(), named),
+                    None => Err(anyhow::anyhow!(
+                        "only tools take named arguments, pass them as an array"
+                    )),
+                }
+            }
+            arg => Ok(vec![arg]),
+        };
+        let args = match args {
+            Ok(args) => args,
+            Err(err) => {
+                let message = error::tool_message(name, &err);
+                let err =
+                    ScriptError::new(error::ErrorKind::Tool, message).with_hint(&self.tools, &[]);
+                return self.run(chat_id, &written, |_| Err(err));
+            }
+        };
+        let code = format!(
+            "{name}({})",
+            args.iter()
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        self.run(chat_id, &code, |engine| engine.call(name, args))
+    }
+
+    fn send(&self, chat_id: ChatId, contont: Token) -> bool {
+        let message = Message {
+            chat_id,
+            role: Role::Tool,
+            contont,
+        };
+        self.tx.send(message).is_ok()
+    }
+
+    fn send_variables(&mut self, chat_id: ChatId) -> bool {
+        let variables = self
+            .engines
+            .get_mut(&chat_id)
+            .map(|engine| engine.variables())
+            .unwrap_or_default();
+        self.send(chat_id, Token::Control(Control::ScriptVariables(variables)))
+    }
+
+    pub fn run_loop(mut self) {
+        loop {
+            let input = match self.approver.take_deferred() {
+                Some(input) => input,
+                None => match self.rx.recv() {
+                    Ok(input) => input,
+                    Err(_) => break,
+                },
+            };
+            let chat_id = input.chat_id;
+            if self.deleted.contains(&chat_id) {
+                continue;
+            }
+            match input {
+                Message {
+                    contont: Token::Control(Control::Settings(settings)),
+                    ..
+                } => {
+                    self.approver.set_policy(settings.approval);
+                    // without an engine there is no executor, that takes a restart
+                    if settings.engine == self.language || settings.engine == "none" {
+                        continue;
+                    }
+                    let reply = match self.set_default_engine(&settings.engine) {
+                        Ok(reset) => {
+                            for chat_id in reset {
+                                let variables = Control::ScriptVariables(Vec::new());
+                                if !self.send(chat_id, Token::Control(variables)) {
+                                    return;
+                                }
+                            }
+                            Control::DefaultEngine(self.language.to_string())
                         }
+                        Err(err) => Control::SettingsError(format!(
+                            "switch to the {} engine error: {err:#}",
+                            settings.engine
+                        )),
+                    };
+                    if !self.send(chat_id, Token::Control(reply)) {
+                        break;
                     }
-                    Err(err) => {
-                        let message = Message {
-                            role: Role::Tool,
-                            contont: Token::End(
-                                serde_json::json!(
-                                    {
-                                        "status":"error",
-                                        "error":err
-                                    }
-                                )
-                                .to_string(),
-                            ),
-                        };
-                        if let Err(_) = self.tx.send(message) {
-                            break;
+                }
+                Message {
+                    contont: Token::Control(Control::DeleteChat),
+                    ..
+                } => {
+                    self.engines.remove(&chat_id);
+                    self.languages.remove(&chat_id);
+                    self.deleted.insert(chat_id);
+                }
+                Message {
+                    contont: Token::Control(Control::SetEngine(language)),
+                    ..
+                } => {
+                    let reply = self.set_engine(chat_id, &language);
+                    if !self.send(chat_id, Token::Control(reply)) || !self.send_variables(chat_id) {
+                        break;
+                    }
+                }
+                Message {
+                    contont: Token::Control(Control::RunScript(code)),
+                    ..
+                } => {
+                    let (result, duration) = self.eval(chat_id, &code);
+                    let result = Control::ScriptResult {
+                        code,
+                        result: result.to_string(),
+                        duration,
+                    };
+                    if !self.send(chat_id, Token::Control(result)) || !self.send_variables(chat_id)
+                    {
+                        break;
+                    }
+                }
+                Message {
+                    contont: Token::Control(Control::ResetScript),
+                    ..
+                } => {
+                    if let Some(engine) = self.engines.get_mut(&chat_id) {
+                        if let Err(err) = engine.reset() {
+                            log::error!("reset script engine error: {err}");
                         }
                     }
+                    if !self.send_variables(chat_id) {
+                        break;
+                    }
                 }
+                Message {
+                    role: Role::Assistant,
+                    contont: Token::End(reply),
+                    ..
+                } => {
+                    let (result, duration) =
+                        match self.protocol.split(&reply, self.language(chat_id)).action {
+                            Some(Action::Script(code)) => self.eval(chat_id, &code),
+                            Some(Action::Call { name, arguments }) => {
+                                self.call(chat_id, &name, arguments)
+                            }
+                            None => continue,
+                        };
+                    let result = result.to_string();
+                    // the model may get less, the user sees all of it
+                    let end = match self.limiter.apply(&result) {
+                        Some(shortened) => Token::ToolEnd {
+                            result: shortened,
+                            full: Some(result),
+                            duration,
+                        },
+                        None => Token::ToolEnd {
+                            result,
+                            full: None,
+                            duration,
+                        },
+                    };
+                    if !self.send(chat_id, end) || !self.send_variables(chat_id) {
+                        break;
+                    }
+                }
+                _ => {}
             }
         }
     }
@@ -70,13 +539,86 @@ pub fn filter(message: &im_channel::Message) -> Option<im_channel::Message> {
         im_channel::Message {
             role: im_channel::Role::Assistant,
             contont: Token::End(contont),
+            ..
         } => {
-            if !contont.is_empty() && !contont.starts_with("//") {
+            if !contont.trim().is_empty() {
                 Some(message.clone())
             } else {
                 None
             }
         }
+        im_channel::Message {
+            role: im_channel::Role::User,
+            contont: Token::Control(_),
+            ..
+        } => Some(message.clone()),
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_always_have_value_and_stdout() {
+        let mut engine = rhai::RhaiEngine::new(Vec::new());
+        let result = engine.eval("40 + 2");
+        assert_eq!(
+            tool_result(&mut engine, result),
+            serde_json::json!({ "value": 42, "stdout": [] })
+        );
+
+        let result = engine.eval(r#"print("hi"); 1"#);
+        assert_eq!(
+            tool_result(&mut engine, result),
+            serde_json::json!({ "value": 1, "stdout": ["hi"] })
+        );
+
+        let result = engine.eval("undefined_function()");
+        let result = tool_result(&mut engine, result);
+        assert_eq!(result["status"], "error");
+        assert_eq!(result["stdout"], serde_json::json!([]));
+    }
+
+    struct SendMsg;
+
+    impl Tool for SendMsg {
+        fn name(&self) -> &str {
+            "send_msg"
+        }
+
+        fn params(&self) -> Vec<String> {
+            vec!["to".to_string(), "text".to_string(), "silent".to_string()]
+        }
+
+        fn call(&self, args: Vec<serde_json::Value>) -> anyhow::Result<serde_json::Value> {
+            Ok(args.into())
+        }
+    }
+
+    fn named(value: serde_json::Value) -> anyhow::Result<Vec<serde_json::Value>> {
+        match value {
+            serde_json::Value::Object(args) => positional_args(&SendMsg, args),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn named_arguments_follow_the_parameters() {
+        let args = named(serde_json::json!({ "text": "hi", "to": 1 })).unwrap();
+        assert_eq!(args, [serde_json::json!(1), "hi".into()]);
+        let args = named(serde_json::json!({ "silent": true, "to": 1 })).unwrap();
+        assert_eq!(args, [1.into(), serde_json::Value::Null, true.into()]);
+        // an explicit null is passed on
+        let args = named(serde_json::json!({ "to": 1, "silent": null })).unwrap();
+        assert_eq!(
+            args,
+            [1.into(), serde_json::Value::Null, serde_json::Value::Null]
+        );
+        assert!(named(serde_json::json!({})).unwrap().is_empty());
+
+        let err = named(serde_json::json!({ "to": 1, "body": "hi" })).unwrap_err();
+        assert!(err.to_string().contains("`body`"), "{err}");
+    }
+}
@@ -0,0 +1,264 @@
+//! Keeps large tool results from filling the prompt.
+
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+
+use super::{Tool, Tools};
+
+const READ_OUTPUT: &str = "read_output";
+
+/// What the model gets when a result is over [`OutputLimit::max_bytes`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// The start of the result and a marker
+    #[default]
+    Truncate,
+    /// The start and the end of the result, with a marker in between
+    HeadTail,
+    /// The first page, the others are read with `read_output(page)`
+    Page,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct OutputLimit {
+    /// bytes of a tool result put into the prompt
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    #[serde(default)]
+    pub overflow: Overflow,
+}
+
+impl Default for OutputLimit {
+    fn default() -> Self {
+        OutputLimit {
+            max_bytes: default_max_bytes(),
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+fn default_max_bytes() -> usize {
+    4 * 1024
+}
+
+/// The largest char boundary of `s` not after `i`
+fn floor_boundary(s: &str, mut i: usize) -> usize {
+    if i >= s.len() {
+        return s.len();
+    }
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+/// The smallest char boundary of `s` not before `i`
+fn ceil_boundary(s: &str, mut i: usize) -> usize {
+    while i < s.len() && !s.is_char_boundary(i) {
+        i += 1;
+    }
+    i
+}
+
+#[derive(Default)]
+struct Pages {
+    output: String,
+    /// the script of this result read a page, don't shorten it again
+    read: bool,
+}
+
+fn page_count(output: &str, page_size: usize) -> usize {
+    output.len().div_ceil(page_size.max(1))
+}
+
+fn page(output: &str, page_size: usize, n: usize) -> &str {
+    let start = floor_boundary(output, (n - 1) * page_size);
+    let end = floor_boundary(output, n * page_size);
+    &output[start..end]
+}
+
+/// Shortens tool results before they go to the model. The paged result is
+/// shared with the `read_output` tool.
+pub struct OutputLimiter {
+    limit: OutputLimit,
+    pages: Arc<Mutex<Pages>>,
+}
+
+impl OutputLimiter {
+    pub fn new(limit: OutputLimit) -> Self {
+        OutputLimiter {
+            limit,
+            pages: Default::default(),
+        }
+    }
+
+    /// `read_output` when results are paged
+    pub fn tools(&self) -> Tools {
+        if self.limit.overflow != Overflow::Page {
+            return Vec::new();
+        }
+        vec![Arc::new(ReadOutput {
+            page_size: self.limit.max_bytes,
+            pages: self.pages.clone(),
+        })]
+    }
+
+    /// Called before each script runs, a page read by an earlier one doesn't count
+    pub fn start_result(&self) {
+        self.pages.lock().unwrap().read = false;
+    }
+
+    /// What the model gets instead of `output`, `None` when it fits
+    pub fn apply(&self, output: &str) -> Option<String> {
+        let max = self.limit.max_bytes;
+        let mut pages = self.pages.lock().unwrap();
+        if std::mem::take(&mut pages.read) || output.len() <= max {
+            return None;
+        }
+
+        let total = output.len();
+        Some(match self.limit.overflow {
+            Overflow::Truncate => {
+                let head = &output[..floor_boundary(output, max)];
+                format!(
+                    "{head}\n...(truncated, {} of {total} bytes shown)",
+                    head.len()
+                )
+            }
+            Overflow::HeadTail => {
+                let head = &output[..floor_boundary(output, max / 2)];
+                let tail = &output[ceil_boundary(output, total - max / 2)..];
+                format!(
+                    "{head}\n...({} bytes omitted)...\n{tail}",
+                    total - head.len() - tail.len()
+                )
+            }
+            Overflow::Page => {
+                pages.output = output.to_string();
+                format!(
+                    "{}\n...(page 1 of {}, call {READ_OUTPUT}(2) for the next page)",
+                    page(output, max, 1),
+                    page_count(output, max)
+                )
+            }
+        })
+    }
+}
+
+struct ReadOutput {
+    page_size: usize,
+    pages: Arc<Mutex<Pages>>,
+}
+
+impl Tool for ReadOutput {
+    fn name(&self) -> &str {
+        READ_OUTPUT
+    }
+
+    fn description(&self) -> &str {
+        "Page `page` of the last tool result that was too long, starting at 1"
+    }
+
+    fn params(&self) -> Vec<String> {
+        vec!["page".to_string()]
+    }
+
+    fn is_pure(&self) -> bool {
+        true
+    }
+
+    fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+        let n = match args.first() {
+            Some(Value::Number(n)) => n.as_u64().unwrap_or_default() as usize,
+            Some(Value::String(s)) => s.parse().unwrap_or_default(),
+            _ => 0,
+        };
+        let mut pages = self.pages.lock().unwrap();
+        let count = page_count(&pages.output, self.page_size);
+        anyhow::ensure!(count > 0, "no result was paged");
+        anyhow::ensure!(
+            (1..=count).contains(&n),
+            "argument #1 `page` must be between 1 and {count}"
+        );
+        pages.read = true;
+        Ok(serde_json::json!({
+            "page": n,
+            "pages": count,
+            "text": page(&pages.output, self.page_size, n),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_bytes: usize, overflow: Overflow) -> OutputLimiter {
+        OutputLimiter::new(OutputLimit {
+            max_bytes,
+            overflow,
+        })
+    }
+
+    #[test]
+    fn short_results_are_kept() {
+        assert_eq!(limiter(10, Overflow::Truncate).apply("0123456789"), None);
+    }
+
+    #[test]
+    fn truncate_keeps_the_head() {
+        let shortened = limiter(4, Overflow::Truncate).apply("0123456789").unwrap();
+        assert_eq!(shortened, "0123\n...(truncated, 4 of 10 bytes shown)");
+    }
+
+    #[test]
+    fn head_tail_keeps_both_ends() {
+        let shortened = limiter(4, Overflow::HeadTail).apply("0123456789").unwrap();
+        assert_eq!(shortened, "01\n...(6 bytes omitted)...\n89");
+    }
+
+    #[test]
+    fn cuts_on_char_boundaries() {
+        let shortened = limiter(4, Overflow::Truncate).apply("ééééé").unwrap();
+        assert!(shortened.starts_with("éé\n"));
+    }
+
+    #[test]
+    fn pages_are_read_with_read_output() {
+        let limiter = limiter(4, Overflow::Page);
+        let read_output = limiter.tools().pop().unwrap();
+        assert!(read_output.call(vec![1.into()]).is_err());
+
+        let shortened = limiter.apply("0123456789").unwrap();
+        assert_eq!(
+            shortened,
+            "0123\n...(page 1 of 3, call read_output(2) for the next page)"
+        );
+        let page = read_output.call(vec![3.into()]).unwrap();
+        assert_eq!(
+            page,
+            serde_json::json!({ "page": 3, "pages": 3, "text": "89" })
+        );
+        assert!(read_output.call(vec![4.into()]).is_err());
+    }
+
+    #[test]
+    fn a_read_page_only_passes_its_own_result() {
+        let limiter = limiter(4, Overflow::Page);
+        let read_output = limiter.tools().pop().unwrap();
+        limiter.apply("0123456789").unwrap();
+
+        // the page is the result of this script
+        limiter.start_result();
+        read_output.call(vec![2.into()]).unwrap();
+        assert_eq!(limiter.apply(r#"{"page":2,"pages":3,"text":"4567"}"#), None);
+
+        // read by `/run`, whose result is not shortened, the next result still is
+        limiter.start_result();
+        read_output.call(vec![2.into()]).unwrap();
+        limiter.start_result();
+        assert!(limiter.apply("0123456789").is_some());
+    }
+}
@@ -0,0 +1,333 @@
+/// How a tool call is written inside an assistant message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// The whole message is a script, a message starting with `//` is a reply.
+    #[default]
+    Script,
+    /// `<reply></reply>` for the user and `<call></call>` for the script.
+    Xml,
+    /// Fenced code blocks tagged with the engine language, e.g. ```` ```lua ````,
+    /// or ```` ```javascript ```` for js.
+    Fenced,
+    /// A `{"name": ..., "arguments": ...}` object.
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Script(String),
+    Call {
+        name: String,
+        /// An array of positional arguments, or an object of named ones
+        arguments: serde_json::Value,
+    },
+}
+
+/// An assistant message split into what the user should read
+/// and what should be executed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Reply {
+    pub text: String,
+    pub action: Option<Action>,
+}
+
+impl Protocol {
+    /// `lang` is the language of the running engine, used by [`Protocol::Fenced`].
+    pub fn split(&self, message: &str, lang: &str) -> Reply {
+        match self {
+            Protocol::Script => split_script(message),
+            Protocol::Xml => split_xml(message),
+            Protocol::Fenced => split_fenced(message, lang),
+            Protocol::Json => split_json(message),
+        }
+    }
+}
+
+fn split_script(message: &str) -> Reply {
+    let message = message.trim();
+    if message.is_empty() {
+        return Reply::default();
+    }
+    if message.starts_with("//") {
+        let text = message
+            .lines()
+            .map(|l| l.trim_start().trim_start_matches("//").trim())
+            .collect::<Vec<_>>()
+            .join("\n");
+        Reply { text, action: None }
+    } else {
+        Reply {
+            text: String::new(),
+            action: Some(Action::Script(message.to_string())),
+        }
+    }
+}
+
+/// Contents of every `<tag>...</tag>` in `s`. An unclosed tag runs to the end.
+fn tag_contents<'a>(s: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let mut contents = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                contents.push(rest[..end].trim());
+                rest = &rest[end + close.len()..];
+            }
+            None => {
+                contents.push(rest.trim());
+                break;
+            }
+        }
+    }
+    contents
+}
+
+fn split_xml(message: &str) -> Reply {
+    let replies = tag_contents(message, "reply");
+    let calls = tag_contents(message, "call");
+
+    let text = if replies.is_empty() && calls.is_empty() {
+        message.trim().to_string()
+    } else {
+        replies.join("\n")
+    };
+    let action = if calls.is_empty() {
+        None
+    } else {
+        Some(Action::Script(calls.join("\n")))
+    };
+    Reply { text, action }
+}
+
+/// The tags a fence of `lang` may have, the engine name first
+fn fence_tags(lang: &str) -> &[&str] {
+    match lang {
+        "js" => &["js", "javascript"],
+        "lua" => &["lua"],
+        "rhai" => &["rhai"],
+        _ => &[],
+    }
+}
+
+fn split_fenced(message: &str, lang: &str) -> Reply {
+    let tags = fence_tags(lang);
+    let mut text = Vec::new();
+    let mut code = Vec::new();
+    let mut in_block: Option<bool> = None;
+    let mut block = Vec::new();
+
+    for line in message.lines() {
+        let trimmed = line.trim_start();
+        match in_block {
+            None if trimmed.starts_with("```") => {
+                let info = trimmed.trim_start_matches('`').trim();
+                let is_script = info
+                    .split_whitespace()
+                    .next()
+                    .is_some_and(|l| tags.iter().any(|tag| l.eq_ignore_ascii_case(tag)));
+                in_block = Some(is_script);
+                if !is_script {
+                    text.push(line);
+                }
+            }
+            None => text.push(line),
+            Some(is_script) if trimmed.starts_with("```") => {
+                if is_script {
+                    code.push(block.join("\n"));
+                    block.clear();
+                } else {
+                    text.push(line);
+                }
+                in_block = None;
+            }
+            Some(true) => block.push(line),
+            Some(false) => text.push(line),
+        }
+    }
+    // an unclosed block is still code, the model may have been cut off by a stop token
+    if in_block == Some(true) && !block.is_empty() {
+        code.push(block.join("\n"));
+    }
+
+    Reply {
+        text: text.join("\n").trim().to_string(),
+        action: if code.is_empty() {
+            None
+        } else {
+            Some(Action::Script(code.join("\n")))
+        },
+    }
+}
+
+fn split_json(message: &str) -> Reply {
+    let (start, end) = match (message.find('{'), message.rfind('}')) {
+        (Some(start), Some(end)) if start < end => (start, end + 1),
+        _ => {
+            return Reply {
+                text: message.trim().to_string(),
+                action: None,
+            }
+        }
+    };
+
+    let call = match serde_json::from_str::<serde_json::Value>(&message[start..end]) {
+        Ok(value) => parse_call(value),
+        Err(_) => None,
+    };
+    match call {
+        Some(action) => {
+            let text = format!("{}{}", &message[..start], &message[end..]);
+            let text = text.replace("```json", "").replace("```", "");
+            Reply {
+                text: text.trim().to_string(),
+                action: Some(action),
+            }
+        }
+        None => Reply {
+            text: message.trim().to_string(),
+            action: None,
+        },
+    }
+}
+
+/// Accepts `{"name": "f", "arguments": {..} | [..]}`, also wrapped in `{"function": ..}`.
+/// An object is kept as is, its keys are matched with the tool's parameters when it runs.
+fn parse_call(mut value: serde_json::Value) -> Option<Action> {
+    if let Some(function) = value.get_mut("function") {
+        value = function.take();
+    }
+    let call = value.as_object_mut()?;
+    let name = call.get("name")?.as_str()?.to_string();
+    let arguments = match call
+        .remove("arguments")
+        .or_else(|| call.remove("parameters"))
+    {
+        None | Some(serde_json::Value::Null) => serde_json::Value::Array(vec![]),
+        // some models send the arguments as a JSON string
+        Some(serde_json::Value::String(s)) => serde_json::from_str(&s).ok()?,
+        Some(v) => v,
+    };
+    let arguments = match arguments {
+        serde_json::Value::Object(_) | serde_json::Value::Array(_) => arguments,
+        v => serde_json::Value::Array(vec![v]),
+    };
+    Some(Action::Call { name, arguments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(code: &str) -> Option<Action> {
+        Some(Action::Script(code.to_string()))
+    }
+
+    #[test]
+    fn script_is_the_whole_message() {
+        let reply = Protocol::Script.split("  send_msg(1, \"hi\")\n", "lua");
+        assert_eq!(reply.action, script("send_msg(1, \"hi\")"));
+
+        let reply = Protocol::Script.split("// hello\n// there", "lua");
+        assert_eq!(reply.text, "hello\nthere");
+        assert_eq!(reply.action, None);
+
+        assert_eq!(Protocol::Script.split("  ", "lua"), Reply::default());
+    }
+
+    #[test]
+    fn xml_tags() {
+        let reply = Protocol::Xml.split(
+            "<reply>one</reply><call>a()</call><reply>two</reply><call>b()</call>",
+            "lua",
+        );
+        assert_eq!(reply.text, "one\ntwo");
+        assert_eq!(reply.action, script("a()\nb()"));
+
+        // cut off by a stop token
+        let reply = Protocol::Xml.split("<reply>wait</reply><call>a(", "lua");
+        assert_eq!(reply.action, script("a("));
+
+        let reply = Protocol::Xml.split(" plain text ", "lua");
+        assert_eq!(reply.text, "plain text");
+        assert_eq!(reply.action, None);
+    }
+
+    #[test]
+    fn fenced_blocks_of_the_engine() {
+        let message =
+            "Let me check.\n```lua\na()\n```\n```python\nnot_run()\n```\nand\n```Lua\nb()\n```";
+        let reply = Protocol::Fenced.split(message, "lua");
+        assert_eq!(reply.action, script("a()\nb()"));
+        assert_eq!(reply.text, "Let me check.\n```python\nnot_run()\n```\nand");
+
+        let reply = Protocol::Fenced.split("```rhai\nlet x = 1;\n```", "lua");
+        assert_eq!(reply.action, None);
+    }
+
+    #[test]
+    fn fenced_aliases() {
+        for tag in ["js", "javascript", "JavaScript"] {
+            let reply = Protocol::Fenced.split(&format!("```{tag}\nf(1)\n```"), "js");
+            assert_eq!(reply.action, script("f(1)"), "{tag}");
+        }
+        let reply = Protocol::Fenced.split("```rhai\nf(1)\n```", "rhai");
+        assert_eq!(reply.action, script("f(1)"));
+        let reply = Protocol::Fenced.split("```javascript\nf(1)\n```", "lua");
+        assert_eq!(reply.action, None);
+    }
+
+    #[test]
+    fn unterminated_fence_is_still_code() {
+        let reply = Protocol::Fenced.split("Sure.\n```lua\na()\nb()", "lua");
+        assert_eq!(reply.text, "Sure.");
+        assert_eq!(reply.action, script("a()\nb()"));
+
+        let reply = Protocol::Fenced.split("Sure.\n```lua\n", "lua");
+        assert_eq!(reply.action, None);
+    }
+
+    #[test]
+    fn json_calls() {
+        let reply = Protocol::Json.split(
+            "Sending.\n```json\n{\"name\": \"send_msg\", \"arguments\": {\"text\": \"hi\", \"to\": 1}}\n```",
+            "lua",
+        );
+        assert_eq!(reply.text, "Sending.");
+        assert_eq!(
+            reply.action,
+            Some(Action::Call {
+                name: "send_msg".to_string(),
+                arguments: serde_json::json!({ "text": "hi", "to": 1 }),
+            })
+        );
+
+        let reply = Protocol::Json.split(
+            r#"{"function": {"name": "f", "arguments": "[1, 2]"}}"#,
+            "lua",
+        );
+        assert_eq!(
+            reply.action,
+            Some(Action::Call {
+                name: "f".to_string(),
+                arguments: serde_json::json!([1, 2]),
+            })
+        );
+
+        let reply = Protocol::Json.split(r#"{"name": "f", "arguments": 1}"#, "lua");
+        assert_eq!(
+            reply.action,
+            Some(Action::Call {
+                name: "f".to_string(),
+                arguments: serde_json::json!([1]),
+            })
+        );
+
+        let reply = Protocol::Json.split("{not json}", "lua");
+        assert_eq!(reply.text, "{not json}");
+        assert_eq!(reply.action, None);
+    }
+}
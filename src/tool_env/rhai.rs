@@ -1,58 +1,334 @@
+use std::{any::TypeId, collections::HashSet};
+
 use rhai::{
     serde::{from_dynamic, to_dynamic},
-    Dynamic, Engine, NativeCallContext,
+    Dynamic, Engine, EvalAltResult, ParseError, Position, Scope, AST,
+};
+
+use super::{
+    error::{self, ErrorKind, ScriptError},
+    SharedStdout, Tools,
 };
 
-fn send_sms(_number: String, _sms_msg: String) -> Result<Dynamic, Box<rhai::EvalAltResult>> {
-    let s = serde_json::json!({
-        "status":"ok"
-    });
-    to_dynamic(s)
+/// rhai has no variadic functions, tools are registered for every arity up to this
+const MAX_TOOL_ARGS: usize = 8;
+
+fn bind_tools(engine: &mut Engine, tools: &Tools) {
+    for tool in tools {
+        for n in 0..=MAX_TOOL_ARGS {
+            let tool = tool.clone();
+            engine.register_raw_fn(
+                tool.name().to_string(),
+                vec![TypeId::of::<Dynamic>(); n],
+                move |_, args| {
+                    let args = args
+                        .iter()
+                        .map(|arg| from_dynamic::<serde_json::Value>(arg))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let r = tool
+                        .call(args)
+                        .map_err(|e| error::tool_message(tool.name(), &e))?;
+                    to_dynamic(r)
+                },
+            );
+        }
+    }
 }
 
-fn send_msg(_room_id: i64, _sms_msg: String) -> Result<Dynamic, Box<rhai::EvalAltResult>> {
-    let s = serde_json::json!({
-        "status":"ok"
-    });
-    to_dynamic(s)
+pub fn new_rhai(stdout: SharedStdout, tools: &Tools) -> Engine {
+    let mut engine = Engine::new();
+    let debug = stdout.clone();
+    engine
+        .on_print(move |s| stdout.borrow_mut().push(s.to_string()))
+        .on_debug(move |s, _, pos| debug.borrow_mut().push(format!("[debug {pos}] {s}")));
+    bind_tools(&mut engine, tools);
+    engine
 }
 
-fn get_weather() -> Result<Dynamic, Box<rhai::EvalAltResult>> {
-    let s = serde_json::json!({
-        "status":"ok",
-        "temp":"18",
-        "weather":"雨"
-    });
-    to_dynamic(s)
+/// Keeps a scope and the script defined functions between evaluations,
+/// so a later snippet can use what an earlier one left behind.
+pub struct RhaiEngine {
+    engine: Engine,
+    scope: Scope<'static>,
+    lib: AST,
+    stdout: SharedStdout,
+    tools: Tools,
+    preludes: Vec<(String, String)>,
+    /// scope entries before this index, and the functions of these `(name, arity)`,
+    /// come from the preludes. rhai overloads by arity, a script may add another one.
+    prelude_vars: usize,
+    prelude_fns: HashSet<(String, usize)>,
 }
 
-fn get_current_time(_context: &NativeCallContext) -> Result<Dynamic, Box<rhai::EvalAltResult>> {
-    let time = chrono::Local::now().to_rfc3339();
-    let s = serde_json::json!({
-        "status":"ok",
-        "time": time
-    });
-    to_dynamic(s)
+impl RhaiEngine {
+    pub fn new(tools: Tools) -> Self {
+        let stdout = SharedStdout::default();
+        RhaiEngine {
+            engine: new_rhai(stdout.clone(), &tools),
+            scope: Scope::new(),
+            lib: AST::empty(),
+            stdout,
+            tools,
+            preludes: Vec::new(),
+            prelude_vars: 0,
+            prelude_fns: HashSet::new(),
+        }
+    }
+
+    fn run(&mut self, ast: AST) -> Result<rhai::Dynamic, Box<rhai::EvalAltResult>> {
+        let ast = self.lib.merge(&ast);
+        self.lib = ast.clone_functions_only();
+        self.engine
+            .eval_ast_with_scope::<rhai::Dynamic>(&mut self.scope, &ast)
+    }
+
+    fn script_error(
+        &mut self,
+        code: &str,
+        kind: ErrorKind,
+        message: String,
+        pos: Position,
+    ) -> ScriptError {
+        let variables = super::ScriptEngin::variables(self);
+        ScriptError::new(kind, message)
+            .at(code, pos.line(), pos.position())
+            .with_hint(&self.tools, &variables)
+    }
+
+    fn parse_error(&mut self, code: &str, err: ParseError) -> ScriptError {
+        self.script_error(
+            code,
+            ErrorKind::Syntax,
+            err.err_type().to_string(),
+            err.position(),
+        )
+    }
+
+    /// The message is the innermost error's, the position the outermost one's,
+    /// which stays in `code` when the error was raised inside a function.
+    fn eval_error(&mut self, code: &str, err: &EvalAltResult) -> ScriptError {
+        let inner = err.unwrap_inner();
+        let (kind, message) = match inner {
+            EvalAltResult::ErrorFunctionNotFound(..) => {
+                (ErrorKind::UnknownFunction, without_position(inner))
+            }
+            EvalAltResult::ErrorRuntime(value, _)
+                if error::is_tool_error(&self.tools, &value.to_string()) =>
+            {
+                (ErrorKind::Tool, value.to_string())
+            }
+            _ => (ErrorKind::Runtime, without_position(inner)),
+        };
+        self.script_error(code, kind, message, err.position())
+    }
 }
 
-pub fn new_rhai() -> Engine {
-    let mut engine = Engine::new();
-    engine
-        .register_fn("send_sms", send_sms)
-        .register_fn("send_msg", send_msg)
-        .register_fn("get_weather", get_weather)
-        .register_fn("get_current_time", get_current_time);
-    engine
+fn without_position(err: &EvalAltResult) -> String {
+    let mut err = err.clone();
+    err.clear_position();
+    err.to_string()
 }
 
-impl super::ScriptEngin for Engine {
-    fn eval(&self, code: &str) -> Result<String, String> {
+impl super::ScriptEngin for RhaiEngine {
+    fn language(&self) -> &'static str {
+        "rhai"
+    }
+
+    fn eval(&mut self, code: &str) -> Result<serde_json::Value, ScriptError> {
+        let ast = match self.engine.compile_with_scope(&self.scope, code) {
+            Ok(ast) => ast,
+            Err(err) => return Err(self.parse_error(code, err)),
+        };
         let r = self
-            .eval::<rhai::Dynamic>(&code)
+            .run(ast)
             .and_then(|d| from_dynamic::<serde_json::Value>(&d));
-        match r {
-            Ok(s) => Ok(s.to_string()),
-            Err(err) => Err(err.to_string()),
+        r.map_err(|err| self.eval_error(code, &err))
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        args: Vec<serde_json::Value>,
+    ) -> Result<serde_json::Value, ScriptError> {
+        if !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(format!("`{name}` is not a function name").into());
+        }
+        // pass the arguments through the scope instead of formatting them into the script
+        let scope_len = self.scope.len();
+        let mut params = Vec::with_capacity(args.len());
+        for (i, arg) in args.into_iter().enumerate() {
+            let arg = to_dynamic(arg).map_err(|e| e.to_string())?;
+            let param = format!("__arg{i}");
+            self.scope.push_dynamic(param.as_str(), arg);
+            params.push(param);
+        }
+        let r = self.eval(&format!("{name}({})", params.join(", ")));
+        self.scope.rewind(scope_len);
+        // the position would point into the generated call
+        r.map_err(|err| ScriptError {
+            line: None,
+            column: None,
+            source: None,
+            ..err
+        })
+    }
+
+    fn load_prelude(&mut self, name: &str, code: &str) -> Result<(), String> {
+        let mut ast = self
+            .engine
+            .compile_with_scope(&self.scope, code)
+            .map_err(|e| format!("{name}: {e}"))?;
+        ast.set_source(name);
+        if let Err(e) = self.run(ast) {
+            return Err(format!("{name}: {e}"));
+        }
+        self.prelude_vars = self.scope.len();
+        self.prelude_fns = self
+            .lib
+            .iter_functions()
+            .map(|f| (f.name.to_string(), f.params.len()))
+            .collect();
+        self.preludes.push((name.to_string(), code.to_string()));
+        Ok(())
+    }
+
+    fn take_stdout(&mut self) -> Vec<String> {
+        self.stdout.borrow_mut().take()
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        self.scope.clear();
+        self.lib.clear_functions();
+        self.prelude_vars = 0;
+        self.prelude_fns.clear();
+        for (name, code) in std::mem::take(&mut self.preludes) {
+            self.load_prelude(&name, &code)?;
+        }
+        self.stdout.borrow_mut().take();
+        Ok(())
+    }
+
+    fn variables(&mut self) -> Vec<(String, String)> {
+        let mut vars: Vec<(String, String)> = Vec::new();
+        for (name, _, value) in self.scope.iter_raw().skip(self.prelude_vars) {
+            let value = from_dynamic::<serde_json::Value>(value)
+                .map(|v| v.to_string())
+                .unwrap_or_else(|_| value.to_string());
+            // shadowed variables are still in the scope, only the latest one is visible
+            match vars.iter_mut().find(|(n, _)| n == name) {
+                Some(var) => var.1 = value,
+                None => vars.push((name.to_string(), value)),
+            }
+        }
+        vars.extend(
+            self.lib
+                .iter_functions()
+                .filter(|f| {
+                    !self
+                        .prelude_fns
+                        .contains(&(f.name.to_string(), f.params.len()))
+                })
+                .map(|f| (f.name.to_string(), format!("fn({})", f.params.join(", ")))),
+        );
+        vars.sort();
+        vars
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::tool_env::{ScriptEngin, Tool};
+
+    /// A tool that refuses every call
+    struct Fail;
+
+    impl Tool for Fail {
+        fn name(&self) -> &str {
+            "fail"
+        }
+
+        fn params(&self) -> Vec<String> {
+            vec!["x".to_string()]
         }
+
+        fn call(&self, _: Vec<serde_json::Value>) -> anyhow::Result<serde_json::Value> {
+            anyhow::bail!("boom")
+        }
+    }
+
+    fn engine() -> RhaiEngine {
+        RhaiEngine::new(vec![Arc::new(Fail) as Arc<dyn Tool>])
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_line_and_column() {
+        let err = engine().eval("let a = 1;\nlet b = ;").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Syntax);
+        assert_eq!(err.line, Some(2));
+        assert!(err.column.is_some());
+        assert_eq!(err.source.as_deref(), Some("let b = ;"));
+    }
+
+    #[test]
+    fn unknown_functions_list_the_available_ones() {
+        let mut rhai = engine();
+        rhai.eval("fn mine(a) { a }").unwrap();
+        let err = rhai.eval("let a = 1;\nnope(a)").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnknownFunction);
+        assert_eq!(err.line, Some(2));
+        assert_eq!(err.column, Some(1));
+        let hint = err.hint.unwrap();
+        assert!(hint.starts_with("available functions: "), "{hint}");
+        assert!(
+            hint.contains("fail(x)") && hint.contains("mine(a)"),
+            "{hint}"
+        );
+    }
+
+    #[test]
+    fn failed_tools_have_their_usage() {
+        let err = engine().eval("let a = 1;\nfail(a)").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Tool);
+        assert_eq!(err.message, "fail: boom");
+        assert_eq!(err.line, Some(2));
+        assert_eq!(err.hint.as_deref(), Some("usage: fail(x)"));
+    }
+
+    #[test]
+    fn definitions_last_until_reset() {
+        let mut rhai = engine();
+        rhai.eval("let x = 41; fn inc(a) { a + 1 }").unwrap();
+        assert_eq!(rhai.eval("inc(x)").unwrap(), serde_json::json!(42));
+
+        rhai.load_prelude("p", "let greeting = \"hi\"; fn helper() { 1 }")
+            .unwrap();
+        rhai.reset().unwrap();
+        assert!(rhai.eval("x").is_err());
+        assert!(rhai.eval("inc(1)").is_err());
+        assert_eq!(rhai.eval("helper()").unwrap(), serde_json::json!(1));
+        assert_eq!(rhai.eval("greeting").unwrap(), serde_json::json!("hi"));
+    }
+
+    #[test]
+    fn variables_leave_out_the_preludes() {
+        let mut rhai = engine();
+        rhai.load_prelude(
+            "p",
+            "let greeting = \"hi\"; fn helper() { 1 } fn both(a) { a }",
+        )
+        .unwrap();
+        assert!(rhai.variables().is_empty());
+        // another arity of a prelude function is the script's own
+        rhai.eval("let x = 1; fn both(a, b) { a + b }").unwrap();
+        assert_eq!(
+            rhai.variables(),
+            [
+                ("both".to_string(), "fn(a, b)".to_string()),
+                ("x".to_string(), "1".to_string()),
+            ]
+        );
     }
 }
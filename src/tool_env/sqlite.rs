@@ -0,0 +1,273 @@
+//! Query tools over one SQLite database.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use rusqlite::{limits::Limit, types::ValueRef, Connection, OpenFlags};
+use serde_json::Value;
+
+use super::{builtin::str_arg, Tool, Tools};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct SqliteConfig {
+    pub path: PathBuf,
+    /// open the file read only and refuse statements that write
+    #[serde(default = "default_read_only")]
+    pub read_only: bool,
+    /// rows returned by one query, the rest are dropped
+    #[serde(default = "default_max_rows")]
+    pub max_rows: usize,
+}
+
+fn default_read_only() -> bool {
+    true
+}
+
+fn default_max_rows() -> usize {
+    100
+}
+
+struct Database {
+    conn: Mutex<Connection>,
+    read_only: bool,
+    max_rows: usize,
+}
+
+fn to_sql(value: &Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value as Sql;
+    match value {
+        Value::Null => Sql::Null,
+        Value::Bool(b) => Sql::Integer(*b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Sql::Integer(i),
+            None => Sql::Real(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => Sql::Text(s.clone()),
+        other => Sql::Text(other.to_string()),
+    }
+}
+
+fn to_json(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+        ValueRef::Blob(b) => b
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+            .into(),
+    }
+}
+
+impl Database {
+    fn open(config: &SqliteConfig) -> anyhow::Result<Self> {
+        let flags = if config.read_only {
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+        } else {
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
+        } | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = Connection::open_with_flags(&config.path, flags)
+            .with_context(|| format!("database `{}`", config.path.display()))?;
+        // an attached file would be a way around `path` and `read_only`
+        conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0);
+        Ok(Database {
+            conn: Mutex::new(conn),
+            read_only: config.read_only,
+            max_rows: config.max_rows,
+        })
+    }
+
+    /// `sql` with `?` placeholders bound from the `params` array
+    fn query(&self, args: &[Value]) -> anyhow::Result<Value> {
+        let sql = str_arg(args, 0, "sql")?;
+        let params = match args.get(1) {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(params)) => params.iter().map(to_sql).collect(),
+            Some(_) => anyhow::bail!("argument #2 `params` must be an array"),
+        };
+
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stmt = conn.prepare(&sql)?;
+        anyhow::ensure!(
+            !self.read_only || stmt.readonly(),
+            "the database is read only"
+        );
+        let params = rusqlite::params_from_iter(params);
+
+        if stmt.column_count() == 0 {
+            let changes = stmt.execute(params)?;
+            return Ok(serde_json::json!({
+                "status": "ok",
+                "changes": changes
+            }));
+        }
+
+        let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+        let mut rows = stmt.query(params)?;
+        let mut result = Vec::new();
+        let mut truncated = false;
+        while let Some(row) = rows.next()? {
+            if result.len() >= self.max_rows {
+                truncated = true;
+                break;
+            }
+            let values = (0..columns.len())
+                .map(|i| row.get_ref(i).map(to_json))
+                .collect::<Result<Vec<_>, _>>()?;
+            result.push(Value::Array(values));
+        }
+        let mut value = serde_json::json!({
+            "columns": columns,
+            "rows": result,
+        });
+        if truncated {
+            value["truncated"] = true.into();
+        }
+        Ok(value)
+    }
+
+    /// The `CREATE` statements, so a model knows what it can query
+    fn schema(&self, _: &[Value]) -> anyhow::Result<Value> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stmt = conn.prepare(
+            "SELECT name, sql FROM sqlite_schema \
+             WHERE type IN ('table', 'view') AND name NOT LIKE 'sqlite_%' ORDER BY name",
+        )?;
+        let tables = stmt
+            .query_map([], |row| {
+                Ok(serde_json::json!({
+                    "name": row.get::<_, String>(0)?,
+                    "sql": row.get::<_, Option<String>>(1)?,
+                }))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tables.into())
+    }
+}
+
+struct SqlTool {
+    name: &'static str,
+    description: &'static str,
+    params: &'static [&'static str],
+    pure: bool,
+    f: fn(&Database, &[Value]) -> anyhow::Result<Value>,
+    db: Arc<Database>,
+}
+
+impl Tool for SqlTool {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn params(&self) -> Vec<String> {
+        self.params.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn is_pure(&self) -> bool {
+        self.pure
+    }
+
+    fn call(&self, args: Vec<Value>) -> anyhow::Result<Value> {
+        (self.f)(&self.db, &args)
+    }
+}
+
+pub fn sqlite_tools(config: &SqliteConfig) -> anyhow::Result<Tools> {
+    let db = Arc::new(Database::open(config)?);
+    let tools = [
+        SqlTool {
+            name: "sql_query",
+            description: if config.read_only {
+                "Run a read only SQL query, `?` placeholders are bound from the `params` array"
+            } else {
+                "Run a SQL statement, `?` placeholders are bound from the `params` array"
+            },
+            params: &["sql", "params"],
+            pure: config.read_only,
+            f: Database::query,
+            db: db.clone(),
+        },
+        SqlTool {
+            name: "sql_schema",
+            description: "The tables and views of the database with their CREATE statements",
+            params: &[],
+            pure: true,
+            f: Database::schema,
+            db,
+        },
+    ];
+    Ok(tools
+        .into_iter()
+        .map(|t| Arc::new(t) as Arc<dyn Tool>)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tools(read_only: bool, max_rows: usize) -> (tempfile::TempDir, Tools) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE t (n INTEGER); INSERT INTO t VALUES (1), (2), (3);")
+            .unwrap();
+        let tools = sqlite_tools(&SqliteConfig {
+            path,
+            read_only,
+            max_rows,
+        })
+        .unwrap();
+        (dir, tools)
+    }
+
+    fn query(tools: &Tools, sql: &str) -> anyhow::Result<Value> {
+        tools[0].call(vec![sql.into()])
+    }
+
+    #[test]
+    fn read_only_refuses_writes() {
+        let (_dir, tools) = tools(true, 100);
+        for sql in ["INSERT INTO t VALUES (4)", "UPDATE t SET n = 0"] {
+            let err = query(&tools, sql).unwrap_err();
+            assert!(err.to_string().contains("read only"), "{sql}: {err}");
+        }
+        assert_eq!(
+            query(&tools, "SELECT count(*) FROM t").unwrap()["rows"],
+            json!([[3]])
+        );
+    }
+
+    #[test]
+    fn rows_past_max_rows_are_truncated() {
+        let (_dir, tools) = tools(true, 2);
+        let result = query(&tools, "SELECT n FROM t ORDER BY n").unwrap();
+        assert_eq!(result["rows"], json!([[1], [2]]));
+        assert_eq!(result["truncated"], json!(true));
+        let result = query(&tools, "SELECT n FROM t WHERE n > 1").unwrap();
+        assert!(result.get("truncated").is_none());
+    }
+
+    #[test]
+    fn other_files_cannot_be_attached() {
+        for read_only in [true, false] {
+            let (dir, tools) = tools(read_only, 100);
+            let other = dir.path().join("other.db");
+            let sql = format!("ATTACH DATABASE '{}' AS other", other.display());
+            assert!(query(&tools, &sql).is_err(), "read_only: {read_only}");
+            assert!(!other.exists());
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Tools implemented by WebAssembly plugins.
+//!
+//! A plugin exports its `memory`, an `alloc(len: i32) -> i32` function and one
+//! `(ptr: i32, len: i32) -> i64` function per tool. A tool gets its arguments as
+//! a UTF-8 JSON array and returns a UTF-8 JSON value packed as `ptr << 32 | len`.
+//! If the plugin exports `dealloc(ptr: i32, len: i32)` both buffers are freed after the call.
+//!
+//! Plugins run under WASI without preopened directories, network or stdio,
+//! with bounded fuel, memory, tables and result size.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use wasmtime::{
+    Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc, ValType,
+};
+use wasmtime_wasi::{preview1::WasiP1Ctx, WasiCtxBuilder};
+
+use super::{Tool, Tools};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct PluginConfig {
+    pub path: String,
+    /// exports to bind as tools, every `(i32, i32) -> i64` export when empty
+    #[serde(default)]
+    pub functions: Vec<String>,
+    /// tools without side effects, every other tool needs the user's approval
+    #[serde(default)]
+    pub pure: Vec<String>,
+    /// fuel given to each call, [`DEFAULT_FUEL`] when unset
+    #[serde(default)]
+    pub fuel: Option<u64>,
+}
+
+/// fuel given to each call when the plugin doesn't set its own
+pub const DEFAULT_FUEL: u64 = 1_000_000_000;
+/// bytes a plugin's linear memories may grow to
+const MAX_MEMORY: usize = 64 << 20;
+/// elements a plugin's tables may grow to
+const MAX_TABLE_ELEMENTS: usize = 10_000;
+/// bytes of JSON a tool may return
+const MAX_OUTPUT: usize = 1 << 20;
+
+struct PluginState {
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+}
+
+struct Plugin {
+    store: Mutex<Store<PluginState>>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    dealloc: Option<TypedFunc<(i32, i32), ()>>,
+    fuel: u64,
+}
+
+pub struct WasmTool {
+    name: String,
+    pure: bool,
+    plugin: Arc<Plugin>,
+    func: TypedFunc<(i32, i32), i64>,
+}
+
+impl Tool for WasmTool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_pure(&self) -> bool {
+        self.pure
+    }
+
+    fn call(&self, args: Vec<serde_json::Value>) -> anyhow::Result<serde_json::Value> {
+        let plugin = &self.plugin;
+        let input = serde_json::to_vec(&args)?;
+        let input_len = i32::try_from(input.len())?;
+
+        let mut store = plugin.store.lock().unwrap();
+        store.set_fuel(plugin.fuel)?;
+
+        let input_ptr = plugin.alloc.call(&mut *store, input_len)?;
+        plugin
+            .memory
+            .write(&mut *store, input_ptr as u32 as usize, &input)?;
+
+        let packed = self.func.call(&mut *store, (input_ptr, input_len))? as u64;
+        let (output_ptr, output_len) = ((packed >> 32) as u32, packed as u32);
+        if output_len as usize > MAX_OUTPUT {
+            anyhow::bail!(
+                "`{}` returned {output_len} bytes, more than the {MAX_OUTPUT} allowed",
+                self.name
+            );
+        }
+        if output_ptr as u64 + output_len as u64 > plugin.memory.data_size(&*store) as u64 {
+            anyhow::bail!(
+                "`{}` returned a result outside of its memory ({output_ptr}+{output_len})",
+                self.name
+            );
+        }
+        let mut output = vec![0; output_len as usize];
+        plugin
+            .memory
+            .read(&*store, output_ptr as usize, &mut output)?;
+
+        if let Some(dealloc) = &plugin.dealloc {
+            dealloc.call(&mut *store, (input_ptr, input_len))?;
+            dealloc.call(&mut *store, (output_ptr as i32, output_len as i32))?;
+        }
+
+        serde_json::from_slice(&output)
+            .with_context(|| format!("`{}` returned invalid JSON", self.name))
+    }
+}
+
+fn load_plugin(engine: &Engine, config: &PluginConfig) -> anyhow::Result<Tools> {
+    let module = Module::from_file(engine, &config.path)?;
+
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |state: &mut PluginState| {
+        &mut state.wasi
+    })?;
+
+    // nothing is inherited: no preopened directories, no sockets, no stdio
+    let wasi = WasiCtxBuilder::new().build_p1();
+    let limits = StoreLimitsBuilder::new()
+        .memory_size(MAX_MEMORY)
+        .table_elements(MAX_TABLE_ELEMENTS)
+        .build();
+    let mut store = Store::new(engine, PluginState { wasi, limits });
+    store.limiter(|state| &mut state.limits);
+    let fuel = config.fuel.unwrap_or(DEFAULT_FUEL);
+    store.set_fuel(fuel)?;
+
+    let instance = linker.instantiate(&mut store, &module)?;
+    if let Ok(init) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+        init.call(&mut store, ())?;
+    }
+
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .ok_or_else(|| anyhow::anyhow!("`memory` is not exported"))?;
+    let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+    let dealloc = instance
+        .get_typed_func::<(i32, i32), ()>(&mut store, "dealloc")
+        .ok();
+
+    let functions = if config.functions.is_empty() {
+        module
+            .exports()
+            .filter(|export| {
+                export.ty().func().is_some_and(|f| {
+                    f.params()
+                        .map(|p| p.matches(&ValType::I32))
+                        .eq([true, true])
+                        && f.results().map(|r| r.matches(&ValType::I64)).eq([true])
+                })
+            })
+            .map(|export| export.name().to_string())
+            .collect()
+    } else {
+        config.functions.clone()
+    };
+
+    let funcs = functions
+        .into_iter()
+        .map(|name| {
+            let func = instance
+                .get_typed_func::<(i32, i32), i64>(&mut store, &name)
+                .with_context(|| format!("tool `{name}`"))?;
+            Ok((name, func))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let plugin = Arc::new(Plugin {
+        store: Mutex::new(store),
+        memory,
+        alloc,
+        dealloc,
+        fuel,
+    });
+
+    Ok(funcs
+        .into_iter()
+        .map(|(name, func)| {
+            Arc::new(WasmTool {
+                pure: config.pure.contains(&name),
+                name,
+                plugin: plugin.clone(),
+                func,
+            }) as Arc<dyn Tool>
+        })
+        .collect())
+}
+
+pub fn load_plugins(configs: &[PluginConfig]) -> anyhow::Result<Tools> {
+    if configs.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut wasm_config = wasmtime::Config::new();
+    wasm_config.consume_fuel(true);
+    let engine = Engine::new(&wasm_config)?;
+
+    let mut tools = Vec::new();
+    for config in configs {
+        let plugin_tools = load_plugin(&engine, config)
+            .with_context(|| format!("load plugin `{}` failed", config.path))?;
+        tools.extend(plugin_tools);
+    }
+    Ok(tools)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// `alloc` hands out a fixed buffer, `true` is at 0 and `false` at 8
+    const WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (table 1 funcref)
+          (data (i32.const 0) "true")
+          (data (i32.const 8) "false")
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "echo") (param i32 i32) (result i64)
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get 0)) (i64.const 32))
+              (i64.extend_i32_u (local.get 1))))
+          (func (export "past_memory") (param i32 i32) (result i64)
+            (i64.const 100000))
+          (func (export "too_long") (param i32 i32) (result i64)
+            (i64.const 0xffffffff))
+          (func (export "grow_memory") (param i32 i32) (result i64)
+            (if (result i64) (i32.eq (memory.grow (i32.const 2000)) (i32.const -1))
+              (then (i64.const 0x800000005))
+              (else (i64.const 4))))
+          (func (export "grow_table") (param i32 i32) (result i64)
+            (if (result i64)
+                (i32.eq (table.grow (ref.null func) (i32.const 100000)) (i32.const -1))
+              (then (i64.const 0x800000005))
+              (else (i64.const 4))))
+          (func (export "spin") (param i32 i32) (result i64)
+            (loop (br 0))
+            (i64.const 4)))
+    "#;
+
+    fn load(fuel: Option<u64>) -> (tempfile::TempDir, Tools) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plugin.wat");
+        std::fs::write(&path, WAT).unwrap();
+        let tools = load_plugins(&[PluginConfig {
+            path: path.to_string_lossy().into_owned(),
+            functions: vec![],
+            pure: vec![],
+            fuel,
+        }])
+        .unwrap();
+        (dir, tools)
+    }
+
+    fn call(
+        tools: &Tools,
+        name: &str,
+        args: Vec<serde_json::Value>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let tool = tools.iter().find(|tool| tool.name() == name).unwrap();
+        tool.call(args)
+    }
+
+    #[test]
+    fn results_are_read_from_memory() {
+        let (_dir, tools) = load(None);
+        assert_eq!(
+            call(&tools, "echo", vec![json!(1), json!("a")]).unwrap(),
+            json!([1, "a"])
+        );
+    }
+
+    #[test]
+    fn results_are_bounds_checked() {
+        let (_dir, tools) = load(None);
+        let err = call(&tools, "past_memory", vec![]).unwrap_err();
+        assert!(err.to_string().contains("outside of its memory"), "{err}");
+        let err = call(&tools, "too_long", vec![]).unwrap_err();
+        assert!(err.to_string().contains("more than"), "{err}");
+    }
+
+    #[test]
+    fn memory_and_tables_are_limited() {
+        let (_dir, tools) = load(None);
+        assert_eq!(call(&tools, "grow_memory", vec![]).unwrap(), json!(false));
+        assert_eq!(call(&tools, "grow_table", vec![]).unwrap(), json!(false));
+    }
+
+    #[test]
+    fn calls_run_out_of_fuel() {
+        let (_dir, tools) = load(Some(10_000));
+        assert!(call(&tools, "spin", vec![]).is_err());
+        // the store is refuelled for the next call
+        assert!(call(&tools, "echo", vec![]).is_ok());
+    }
+}